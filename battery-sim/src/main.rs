@@ -5,11 +5,14 @@ use std::{
     time::Instant,
 };
 
-use chrono::{TimeDelta, Utc};
+use anyhow::bail;
+use chrono::TimeDelta;
+use p1::ElectricityData;
+use prices::{Price, Tariff};
 use rayon::slice::ParallelSliceMut;
-use serde::Deserialize;
 
 use clap::Parser;
+use settings::BatterySimConfig;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -18,10 +21,24 @@ struct Cli {
     json_path: PathBuf,
     #[arg(short, long, value_name = "FILE")]
     battery_stats_out: Option<PathBuf>,
+    /// ENTSO-E XML or CSV files with the day-ahead prices of a dynamic tariff
+    #[arg(short, long, value_name = "FILE")]
+    prices: Vec<PathBuf>,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
+    let config = BatterySimConfig::load()?;
+
+    let mut day_ahead_prices = Vec::new();
+    for path in &args.prices {
+        println!("Loading prices from {}...", path.display());
+        day_ahead_prices.extend(prices::read_file(path)?);
+    }
+    day_ahead_prices.sort_by_key(|price| price.time);
+    if matches!(config.tariff, Tariff::Dynamic { .. }) && day_ahead_prices.is_empty() {
+        bail!("A dynamic tariff needs the day-ahead prices, pass them with --prices");
+    }
 
     println!("Opening file {}...", args.json_path.display());
     let mut json_file = OpenOptions::new()
@@ -104,245 +121,39 @@ fn main() -> anyhow::Result<()> {
         },
         Strategy::NetZero,
         &loaded_data,
+        &config.tariff,
+        &day_ahead_prices,
     );
     println!("Done: {baseline_report:?}");
+    println!("Total cost €{:.2}", baseline_report.cost);
+    if baseline_report.unpriced > 0.0 {
+        println!(
+            "{:.2} kWh was left out of the cost, there was no price for it",
+            baseline_report.unpriced
+        );
+    }
 
-    println!("\nRunning 0.1kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 0.1,
-            max_charging_rate: 5.0,
-            max_discharging_rate: 5.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 0.2kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 0.2,
-            max_charging_rate: 5.0,
-            max_discharging_rate: 5.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 0.5kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 0.5,
-            max_charging_rate: 5.0,
-            max_discharging_rate: 5.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 1kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 1.0,
-            max_charging_rate: 5.0,
-            max_discharging_rate: 5.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 2kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 2.0,
-            max_charging_rate: 5.0,
-            max_discharging_rate: 5.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 5kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 5.0,
-            max_charging_rate: 5.0,
-            max_discharging_rate: 5.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 10kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 10.0,
-            max_charging_rate: 5.0,
-            max_discharging_rate: 5.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 20kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 20.0,
-            max_charging_rate: 10.0,
-            max_discharging_rate: 10.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 50kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 50.0,
-            max_charging_rate: 10.0,
-            max_discharging_rate: 10.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 100kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 100.0,
-            max_charging_rate: 10.0,
-            max_discharging_rate: 10.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 200kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 200.0,
-            max_charging_rate: 10.0,
-            max_discharging_rate: 10.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 500kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 500.0,
-            max_charging_rate: 10.0,
-            max_discharging_rate: 10.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 1000kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 1000.0,
-            max_charging_rate: 10.0,
-            max_discharging_rate: 10.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 2000kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 2000.0,
-            max_charging_rate: 10.0,
-            max_discharging_rate: 10.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
-
-    println!("\nRunning 5000kWh sim...");
-    run_battery_sim(
-        Battery {
-            stored: 0.0,
-            capacity: 5000.0,
-            max_charging_rate: 10.0,
-            max_discharging_rate: 10.0,
-            efficiency: 0.95,
-        },
-        Strategy::NetZero,
-        &loaded_data,
-    )
-    .display_comparison(baseline_report);
+    for battery in &config.batteries {
+        println!("\nRunning {}kWh sim...", battery.capacity);
+        run_battery_sim(
+            Battery {
+                stored: 0.0,
+                capacity: battery.capacity,
+                max_charging_rate: battery.max_charging_rate,
+                max_discharging_rate: battery.max_discharging_rate,
+                efficiency: battery.efficiency,
+            },
+            Strategy::NetZero,
+            &loaded_data,
+            &config.tariff,
+            &day_ahead_prices,
+        )
+        .display_comparison(baseline_report);
+    }
 
     Ok(())
 }
 
-#[derive(Debug, Default, Deserialize)]
-struct ElectricityData {
-    time: chrono::DateTime<Utc>,
-
-    kwh_import_total_tarif_low: f32,
-    kwh_import_total_tarif_high: f32,
-    kwh_export_total_tarif_low: f32,
-    kwh_export_total_tarif_high: f32,
-
-    voltages: [f32; 3],
-    active_powers_import: [f32; 3],
-    active_powers_export: [f32; 3],
-}
-
-impl ElectricityData {
-    /// kwh balance, positive for import, negative for export
-    fn kwh_balance(&self) -> f32 {
-        self.kwh_import_total_tarif_low + self.kwh_import_total_tarif_high
-            - self.kwh_export_total_tarif_high
-            - self.kwh_export_total_tarif_low
-    }
-}
-
 struct Battery {
     // kWh
     stored: f32,
@@ -360,10 +171,14 @@ fn run_battery_sim(
     mut battery: Battery,
     strategy: Strategy,
     mut data_to_go: &[ElectricityData],
+    tariff: &Tariff,
+    day_ahead_prices: &[Price],
 ) -> Report {
     let mut report = Report {
         total_export: 0.0,
         total_import: 0.0,
+        cost: 0.0,
+        unpriced: 0.0,
     };
 
     let mut prev = &data_to_go[0];
@@ -391,10 +206,23 @@ fn run_battery_sim(
         let real_change_in_period = curr.kwh_balance() - prev.kwh_balance();
         let simmed_change_in_period = real_change_in_period + clamped_charging;
 
+        // Meters only count up the registers of the current tariff period
+        let high = curr.kwh_import_total_tarif_low + curr.kwh_export_total_tarif_low
+            == prev.kwh_import_total_tarif_low + prev.kwh_export_total_tarif_low;
+        let spot = prices::price_at(day_ahead_prices, prev.time);
+
         if simmed_change_in_period > 0.0 {
             report.total_import += simmed_change_in_period;
+            match tariff.import_price(high, spot) {
+                Some(price) => report.cost += simmed_change_in_period * price,
+                None => report.unpriced += simmed_change_in_period,
+            }
         } else {
             report.total_export -= simmed_change_in_period;
+            match tariff.export_price(high, spot) {
+                Some(price) => report.cost += simmed_change_in_period * price,
+                None => report.unpriced -= simmed_change_in_period,
+            }
         }
 
         prev = curr;
@@ -404,18 +232,19 @@ fn run_battery_sim(
     report
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 struct Report {
     // kWh
     total_import: f32,
     // kWh
     total_export: f32,
+    // €, imports minus what exports paid
+    cost: f32,
+    // kWh imported or exported without a price
+    unpriced: f32,
 }
 
 impl Report {
-    const IMPORT_COST: f32 = 0.25;
-    const EXPORT_COST: f32 = -0.01;
-
     fn display_comparison(&self, baseline: Report) {
         println!("-------- Report difference --------");
         println!(
@@ -428,26 +257,7 @@ impl Report {
             self.total_export,
             self.total_export - baseline.total_export
         );
-        println!(
-            "Cost €{:.2} (€{:.2})",
-            self.total_import * Self::IMPORT_COST + self.total_export * Self::EXPORT_COST,
-            self.total_export * Self::EXPORT_COST - baseline.total_export * Self::EXPORT_COST
-                + self.total_import * Self::IMPORT_COST
-                - baseline.total_import * Self::IMPORT_COST
-        );
-    }
-}
-
-impl std::fmt::Debug for Report {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Report")
-            .field("total_import", &self.total_import)
-            .field("total_export", &self.total_export)
-            .field(
-                "Total cost",
-                &(self.total_import * Self::IMPORT_COST + self.total_export * Self::EXPORT_COST),
-            )
-            .finish()
+        println!("Cost €{:.2} (€{:.2})", self.cost, self.cost - baseline.cost);
     }
 }
 
@@ -2,7 +2,10 @@ use std::{
     future,
     mem::transmute,
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
 };
 
 use tokio::net::TcpListener;
@@ -100,6 +103,13 @@ pub struct InstantaneousData {
     pub kwh_neg_total: i32,
 }
 
+/// Number of Modbus clients connected to the grid meter servers in this process
+static CONNECTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn connected_clients() -> usize {
+    CONNECTED_CLIENTS.load(Ordering::Relaxed)
+}
+
 struct GridMeterService {
     instantaneous_data: Arc<Mutex<InstantaneousData>>,
     measuring_system: MeasuringSystem,
@@ -132,16 +142,16 @@ impl tokio_modbus::server::Service for GridMeterService {
                     )), // Serial number, e.g.: b"BY24600320011\0"
                     (0xA100, 1) => Ok(Response::ReadHoldingRegisters(vec![0x0000])), // Front selector status: 0
                     (0x0000, 80) => Ok(Response::ReadHoldingRegisters({
-                        let mut data = self.instantaneous_data.lock().unwrap();
+                        let data = self.instantaneous_data.lock().unwrap();
                         let data = data.clone();
-                        let words = unsafe { transmute::<_, [u16; 80]>(data) };
+                        let words = unsafe { transmute::<InstantaneousData, [u16; 80]>(data) };
                         words.to_vec()
                     })), // Instantaneous data
                     _ => Err(ExceptionCode::IllegalFunction),
                 }
             }
             _ => {
-                println!(
+                tracing::warn!(
                     "SERVER: Exception::IllegalFunction - Unimplemented function code in request: {req:?}"
                 );
                 Err(ExceptionCode::IllegalFunction)
@@ -157,6 +167,7 @@ impl GridMeterService {
         measuring_system: MeasuringSystem,
         serial_number: &'static [u8],
     ) -> Self {
+        CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
         Self {
             instantaneous_data,
             measuring_system,
@@ -165,13 +176,19 @@ impl GridMeterService {
     }
 }
 
+impl Drop for GridMeterService {
+    fn drop(&mut self) {
+        CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 pub async fn run_grid_meter_server(
     socket_addr: SocketAddr,
     instantaneous_data: Arc<Mutex<InstantaneousData>>,
     measuring_system: MeasuringSystem,
     serial_number: &'static [u8],
 ) -> anyhow::Result<()> {
-    println!("Starting up grid meter server on {socket_addr}");
+    tracing::info!("Starting up grid meter server on {socket_addr}");
     let listener = TcpListener::bind(socket_addr).await?;
     let server = Server::new(listener);
     let new_service = |_socket_addr| {
@@ -185,7 +202,7 @@ pub async fn run_grid_meter_server(
         accept_tcp_connection(stream, socket_addr, new_service)
     };
     let on_process_error = |err| {
-        eprintln!("{err}");
+        tracing::warn!("Grid meter client error: {err}");
     };
     server.serve(&on_connected, on_process_error).await?;
     Ok(())
@@ -205,3 +222,21 @@ pub enum MeasuringSystem {
     /// 3P
     Setup3P = 4,
 }
+
+impl std::str::FromStr for MeasuringSystem {
+    type Err = String;
+
+    /// Parses the names the meter uses, e.g. `3P.N`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "3P.N" => Ok(Self::Setup3PN),
+            "3P.1" => Ok(Self::Setup3P1),
+            "2P" => Ok(Self::Setup2P),
+            "1P" => Ok(Self::Setup1P),
+            "3P" => Ok(Self::Setup3P),
+            _ => Err(format!(
+                "unknown measuring system {s:?}, expected one of 3P.N, 3P.1, 2P, 1P or 3P"
+            )),
+        }
+    }
+}
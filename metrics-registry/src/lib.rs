@@ -0,0 +1,44 @@
+//! The Prometheus registry the binaries serve their metrics from, with the metrics they all have
+
+use prometheus::{Encoder, IntGauge, Registry, TextEncoder, core::Collector};
+
+pub struct MetricsRegistry {
+    registry: Registry,
+    grid_meter_clients: IntGauge,
+}
+
+impl MetricsRegistry {
+    /// All metrics are named `<prefix>_<name>`
+    pub fn new(prefix: &str) -> Self {
+        let registry = Registry::new_custom(Some(prefix.into()), None).unwrap();
+        let grid_meter_clients = IntGauge::new(
+            "grid_meter_clients",
+            "Modbus clients connected to the grid meter server",
+        )
+        .unwrap();
+        registry
+            .register(Box::new(grid_meter_clients.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            grid_meter_clients,
+        }
+    }
+
+    pub fn register(&self, collector: Box<dyn Collector>) {
+        self.registry.register(collector).unwrap();
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        self.grid_meter_clients
+            .set(grid_meter::connected_clients() as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
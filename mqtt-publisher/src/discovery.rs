@@ -0,0 +1,189 @@
+//! Home Assistant MQTT discovery, see <https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery>
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Device {
+    pub identifiers: Vec<String>,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+impl Device {
+    /// Used in topics and unique ids, so only keep the characters that are allowed there
+    fn node_id(&self) -> String {
+        self.identifiers
+            .first()
+            .unwrap_or(&self.name)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    }
+}
+
+/// What a sensor measures. Determines the device class, state class and unit Home Assistant gets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Quantity {
+    /// V
+    Voltage,
+    /// A
+    Current,
+    /// W
+    Power,
+    /// kW
+    PowerKilowatt,
+    /// kWh, only ever increasing
+    EnergyKilowattHour,
+    /// GJ, only ever increasing
+    EnergyGigajoule,
+    /// m³, only ever increasing
+    Gas,
+    /// m³, only ever increasing
+    Water,
+    /// °C
+    Temperature,
+    /// Only ever increasing value with no known device class
+    Total(String),
+}
+
+impl Quantity {
+    fn device_class(&self) -> Option<&'static str> {
+        match self {
+            Quantity::Voltage => Some("voltage"),
+            Quantity::Current => Some("current"),
+            Quantity::Power | Quantity::PowerKilowatt => Some("power"),
+            Quantity::EnergyKilowattHour | Quantity::EnergyGigajoule => Some("energy"),
+            Quantity::Gas => Some("gas"),
+            Quantity::Water => Some("water"),
+            Quantity::Temperature => Some("temperature"),
+            Quantity::Total(_) => None,
+        }
+    }
+
+    fn state_class(&self) -> &'static str {
+        match self {
+            Quantity::Voltage
+            | Quantity::Current
+            | Quantity::Power
+            | Quantity::PowerKilowatt
+            | Quantity::Temperature => "measurement",
+            Quantity::EnergyKilowattHour
+            | Quantity::EnergyGigajoule
+            | Quantity::Gas
+            | Quantity::Water
+            | Quantity::Total(_) => "total_increasing",
+        }
+    }
+
+    fn unit(&self) -> &str {
+        match self {
+            Quantity::Voltage => "V",
+            Quantity::Current => "A",
+            Quantity::Power => "W",
+            Quantity::PowerKilowatt => "kW",
+            Quantity::EnergyKilowattHour => "kWh",
+            Quantity::EnergyGigajoule => "GJ",
+            Quantity::Gas | Quantity::Water => "m³",
+            Quantity::Temperature => "°C",
+            Quantity::Total(unit) => unit,
+        }
+    }
+}
+
+/// A single sensor entity
+#[derive(Debug, Clone, Serialize)]
+pub struct SensorConfig {
+    #[serde(skip)]
+    object_id: String,
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    value_template: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    state_class: &'static str,
+    unit_of_measurement: String,
+    device: Device,
+}
+
+impl SensorConfig {
+    /// - `object_id`: Unique within the device, e.g. `voltage_l1`
+    /// - `state_topic`: The full topic that contains the json state
+    /// - `value_template`: Jinja template to get the value out of the json, e.g. `{{ value_json.voltages[0] }}`
+    pub fn new(
+        device: &Device,
+        object_id: &str,
+        name: &str,
+        quantity: Quantity,
+        state_topic: String,
+        value_template: &str,
+    ) -> Self {
+        Self {
+            object_id: object_id.into(),
+            name: name.into(),
+            unique_id: format!("{}_{object_id}", device.node_id()),
+            state_topic,
+            value_template: value_template.into(),
+            device_class: quantity.device_class(),
+            state_class: quantity.state_class(),
+            unit_of_measurement: quantity.unit().into(),
+            device: device.clone(),
+        }
+    }
+
+    pub(crate) fn discovery_topic(&self) -> String {
+        format!(
+            "homeassistant/sensor/{}/{}/config",
+            self.device.node_id(),
+            self.object_id
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensor_config() {
+        let device = Device {
+            identifiers: vec!["E0043007052870318".into()],
+            name: "P1 meter".into(),
+            manufacturer: None,
+            model: Some("DSMR 5".into()),
+        };
+        let config = SensorConfig::new(
+            &device,
+            "energy_import_low",
+            "Energy import low tariff",
+            Quantity::EnergyKilowattHour,
+            "p1/electricity".into(),
+            "{{ value_json.kwh_import_total_tarif_low }}",
+        );
+
+        assert_eq!(
+            config.discovery_topic(),
+            "homeassistant/sensor/E0043007052870318/energy_import_low/config"
+        );
+        assert_eq!(
+            serde_json::to_value(&config).unwrap(),
+            serde_json::json!({
+                "name": "Energy import low tariff",
+                "unique_id": "E0043007052870318_energy_import_low",
+                "state_topic": "p1/electricity",
+                "value_template": "{{ value_json.kwh_import_total_tarif_low }}",
+                "device_class": "energy",
+                "state_class": "total_increasing",
+                "unit_of_measurement": "kWh",
+                "device": {
+                    "identifiers": ["E0043007052870318"],
+                    "name": "P1 meter",
+                    "model": "DSMR 5"
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,143 @@
+#![allow(clippy::type_complexity)]
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::Serialize;
+
+pub mod discovery;
+
+const HOME_ASSISTANT_STATUS_TOPIC: &str = "homeassistant/status";
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    /// All topics are published under this prefix
+    pub topic_prefix: String,
+    pub qos: QoS,
+    /// Retain the messages so new subscribers get the last value right away
+    pub retain: bool,
+}
+
+/// Publishes json messages to an MQTT broker.
+///
+/// Publishing never blocks. When the broker is unreachable, messages are dropped
+/// while the connection is retried in the background. Clones publish over the same connection.
+#[derive(Clone)]
+pub struct MqttPublisher {
+    client: AsyncClient,
+    config: MqttConfig,
+    /// Home Assistant discovery configs, sent again on every (re)connect and when Home Assistant restarts
+    discovery: Arc<Mutex<Vec<(String, Vec<u8>)>>>,
+}
+
+impl MqttPublisher {
+    pub fn start(config: MqttConfig) -> Self {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some((username, password)) = &config.credentials {
+            options.set_credentials(username, password);
+        }
+
+        let (client, mut event_loop) = AsyncClient::new(options, 256);
+        let discovery = Arc::new(Mutex::new(Vec::new()));
+
+        tokio::spawn({
+            let config = config.clone();
+            let client = client.clone();
+            let discovery = discovery.clone();
+            async move {
+                let mut connected = false;
+                loop {
+                    match event_loop.poll().await {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            connected = true;
+                            tracing::info!(
+                                "Connected to MQTT broker {}:{}",
+                                config.host,
+                                config.port
+                            );
+
+                            let _ =
+                                client.try_subscribe(HOME_ASSISTANT_STATUS_TOPIC, QoS::AtLeastOnce);
+                            publish_discovery(&client, &discovery);
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish)))
+                            if publish.topic == HOME_ASSISTANT_STATUS_TOPIC
+                                && &publish.payload[..] == b"online" =>
+                        {
+                            publish_discovery(&client, &discovery);
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            if connected {
+                                connected = false;
+                                tracing::warn!("MQTT connection error, reconnecting: {e}");
+                            }
+                            tokio::time::sleep(Duration::from_secs(5)).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            client,
+            config,
+            discovery,
+        }
+    }
+
+    /// The full topic name for a topic under the configured prefix
+    pub fn topic(&self, topic: &str) -> String {
+        format!("{}/{topic}", self.config.topic_prefix)
+    }
+
+    /// Publish to a topic under the configured prefix
+    pub fn publish_json(&self, topic: &str, payload: &impl Serialize) {
+        let payload = match serde_json::to_vec(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::error!("Could not serialize MQTT message: {e}");
+                return;
+            }
+        };
+
+        // Fails when the request queue is full, which happens when the broker is unreachable
+        let _ = self.client.try_publish(
+            self.topic(topic),
+            self.config.qos,
+            self.config.retain,
+            payload,
+        );
+    }
+
+    /// Replace the Home Assistant discovery configs and publish them
+    pub fn set_discovery(&self, configs: &[discovery::SensorConfig]) {
+        let messages = configs
+            .iter()
+            .filter_map(|config| match serde_json::to_vec(config) {
+                Ok(payload) => Some((config.discovery_topic(), payload)),
+                Err(e) => {
+                    tracing::error!("Could not serialize discovery config: {e}");
+                    None
+                }
+            })
+            .collect();
+
+        *self.discovery.lock().unwrap() = messages;
+        publish_discovery(&self.client, &self.discovery);
+    }
+}
+
+fn publish_discovery(client: &AsyncClient, discovery: &Mutex<Vec<(String, Vec<u8>)>>) {
+    for (topic, payload) in discovery.lock().unwrap().iter() {
+        let _ = client.try_publish(topic, QoS::AtLeastOnce, true, payload.clone());
+    }
+}
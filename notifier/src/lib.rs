@@ -0,0 +1,430 @@
+//! Sends notifications about events, like an alarm or a lost connection, to webhooks and local commands.
+//!
+//! Every sink gets its own task and queue, so a slow or unreachable sink doesn't hold up the others or the
+//! caller. Failed sends are retried with a growing delay.
+
+use std::{collections::BTreeMap, process::ExitStatus, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+mod template;
+
+pub use template::render;
+
+/// Notifications waiting per sink, newer ones are dropped when it's full
+const QUEUE_SIZE: usize = 64;
+/// The delay before the first retry, doubled for every next one
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// For one webhook request or command
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Something that happened, along with a default title and message
+#[derive(Debug, Clone)]
+pub struct Notification {
+    /// e.g. `alarm_raised`, sinks can be limited to some events
+    pub event: &'static str,
+    pub priority: Priority,
+    pub title: String,
+    pub message: String,
+    /// Details of the event, available to templates as `{name}`
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl Notification {
+    pub fn new(
+        event: &'static str,
+        priority: Priority,
+        title: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            event,
+            priority,
+            title: title.into(),
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.fields.push((name, value.to_string()));
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    Default,
+    High,
+}
+
+/// Where notifications go, see `[[reader.notifications]]` in the example config
+#[derive(Debug, Clone)]
+pub struct SinkConfig {
+    pub target: Target,
+    /// Only these events are sent, all of them when empty
+    pub events: Vec<String>,
+    /// Templates replacing the notification's own title and message
+    pub title: Option<String>,
+    pub message: Option<String>,
+    /// How many times a failed send is tried again
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// POST json to the URL
+    Webhook {
+        url: String,
+        format: WebhookFormat,
+        /// Sent as a bearer token, or as `X-Gotify-Key` to Gotify
+        token: Option<String>,
+    },
+    /// Run a program with arguments, which are templates
+    Command(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WebhookFormat {
+    /// The event, title, message, priority and fields
+    Json,
+    /// A ntfy publish request, the URL is the server's root
+    Ntfy { topic: String },
+    /// A Gotify message, the URL ends in `/message`
+    Gotify,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum SendError {
+    #[error("{0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Could not run command: {0}")]
+    Command(#[from] std::io::Error),
+    #[error("Command failed with {0}")]
+    Exit(ExitStatus),
+    #[error("Command timed out")]
+    Timeout,
+}
+
+impl SendError {
+    /// Requests the webhook rejected will be rejected again
+    fn retryable(&self) -> bool {
+        match self {
+            Self::Http(e) => e.status().is_none_or(|status| {
+                !status.is_client_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }),
+            Self::Command(_) => false,
+            Self::Exit(_) | Self::Timeout => true,
+        }
+    }
+}
+
+enum Message {
+    Notify(Arc<Notification>),
+    /// Answered once everything before it was sent or given up on
+    Flush(oneshot::Sender<()>),
+}
+
+/// Hands notifications to the sinks. Cheap to clone, clones share the sinks.
+///
+/// Without sinks, notifying does nothing.
+#[derive(Clone, Default)]
+pub struct Notifier {
+    sinks: Vec<(Arc<Vec<String>>, mpsc::Sender<Message>)>,
+}
+
+impl Notifier {
+    /// Spawns a task per sink, so it needs a Tokio runtime
+    pub fn start(sinks: Vec<SinkConfig>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(SEND_TIMEOUT)
+            .build()
+            .expect("Failed to build HTTP client");
+
+        let sinks = sinks
+            .into_iter()
+            .map(|sink| {
+                let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+                let events = Arc::new(sink.events.clone());
+                tokio::spawn(run_sink(sink, client.clone(), rx));
+                (events, tx)
+            })
+            .collect();
+
+        Self { sinks }
+    }
+
+    /// Queues the notification for every sink that wants it. Never blocks.
+    pub fn notify(&self, notification: Notification) {
+        let notification = Arc::new(notification);
+
+        for (events, tx) in &self.sinks {
+            if !events.is_empty() && !events.iter().any(|event| event == notification.event) {
+                continue;
+            }
+            if tx.try_send(Message::Notify(notification.clone())).is_err() {
+                tracing::error!(
+                    event = notification.event,
+                    "Dropped notification, the sink is too far behind"
+                );
+            }
+        }
+    }
+
+    /// Waits until everything notified before was sent or given up on, for at most `timeout`.
+    ///
+    /// For when the binary is about to exit.
+    pub async fn flush(&self, timeout: Duration) {
+        let flushes = self.sinks.iter().map(|(_, tx)| async move {
+            let (done_tx, done_rx) = oneshot::channel();
+            if tx.send(Message::Flush(done_tx)).await.is_ok() {
+                let _ = done_rx.await;
+            }
+        });
+
+        let all = async {
+            for flush in flushes {
+                flush.await;
+            }
+        };
+        if tokio::time::timeout(timeout, all).await.is_err() {
+            tracing::warn!("Gave up waiting for notifications to be sent");
+        }
+    }
+}
+
+async fn run_sink(sink: SinkConfig, client: reqwest::Client, mut rx: mpsc::Receiver<Message>) {
+    while let Some(message) = rx.recv().await {
+        let notification = match message {
+            Message::Notify(notification) => notification,
+            Message::Flush(done_tx) => {
+                let _ = done_tx.send(());
+                continue;
+            }
+        };
+
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 0..=sink.retries {
+            let result = send(&sink, &client, &notification).await;
+            match result {
+                Ok(()) => break,
+                Err(e) if e.retryable() && attempt < sink.retries => {
+                    tracing::debug!(
+                        event = notification.event,
+                        "Could not send notification, trying again in {delay:?}: {e}"
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        event = notification.event,
+                        "Could not send notification: {e}"
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn send(
+    sink: &SinkConfig,
+    client: &reqwest::Client,
+    notification: &Notification,
+) -> Result<(), SendError> {
+    let title = match &sink.title {
+        Some(template) => render(template, notification),
+        None => notification.title.clone(),
+    };
+    let message = match &sink.message {
+        Some(template) => render(template, notification),
+        None => notification.message.clone(),
+    };
+
+    match &sink.target {
+        Target::Webhook { url, format, token } => {
+            let body = webhook_body(format, notification, &title, &message);
+            let mut request = client.post(url).json(&body);
+            request = match (format, token) {
+                (WebhookFormat::Gotify, Some(token)) => request.header("X-Gotify-Key", token),
+                (_, Some(token)) => request.bearer_auth(token),
+                (_, None) => request,
+            };
+            request.send().await?.error_for_status()?;
+            Ok(())
+        }
+        Target::Command(command) => {
+            let (program, args) = command.split_first().expect("Empty command");
+            let mut child = tokio::process::Command::new(program)
+                .args(args.iter().map(|arg| render(arg, notification)))
+                .env("NOTIFY_EVENT", notification.event)
+                .env("NOTIFY_TITLE", &title)
+                .env("NOTIFY_MESSAGE", &message)
+                .kill_on_drop(true)
+                .spawn()?;
+            match tokio::time::timeout(SEND_TIMEOUT, child.wait()).await {
+                Ok(status) => {
+                    let status = status?;
+                    if status.success() {
+                        Ok(())
+                    } else {
+                        Err(SendError::Exit(status))
+                    }
+                }
+                Err(_) => Err(SendError::Timeout),
+            }
+        }
+    }
+}
+
+fn webhook_body(
+    format: &WebhookFormat,
+    notification: &Notification,
+    title: &str,
+    message: &str,
+) -> serde_json::Value {
+    match format {
+        WebhookFormat::Json => serde_json::json!({
+            "event": notification.event,
+            "priority": notification.priority,
+            "title": title,
+            "message": message,
+            "fields": notification
+                .fields
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect::<BTreeMap<_, _>>(),
+        }),
+        // ntfy priorities go from 1 (min) to 5 (max)
+        WebhookFormat::Ntfy { topic } => serde_json::json!({
+            "topic": topic,
+            "title": title,
+            "message": message,
+            "priority": match notification.priority {
+                Priority::Low => 2,
+                Priority::Default => 3,
+                Priority::High => 4,
+            },
+            "tags": [notification.event],
+        }),
+        // Gotify priorities go from 0 to 10, clients only make a sound from 4 and up by default
+        WebhookFormat::Gotify => serde_json::json!({
+            "title": title,
+            "message": message,
+            "priority": match notification.priority {
+                Priority::Low => 2,
+                Priority::Default => 5,
+                Priority::High => 8,
+            },
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answers requests with the given statuses in turn, and sends the request bodies back
+    async fn webhook_stand_in(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (body_tx, body_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // Read until the whole body, which has a content length, is in
+                let body = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_ascii_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(|length| length.parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break body.to_string();
+                        }
+                    }
+                };
+                body_tx.send(body).unwrap();
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {status} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (url, body_rx)
+    }
+
+    #[tokio::test]
+    async fn webhook_is_retried_and_templated() {
+        let (url, mut bodies) = webhook_stand_in(vec![503, 200]).await;
+        let notifier = Notifier::start(vec![SinkConfig {
+            target: Target::Webhook {
+                url,
+                format: WebhookFormat::Ntfy { topic: "p1".into() },
+                token: None,
+            },
+            events: vec!["alarm_raised".into()],
+            title: Some("{meter_id}: {title}".into()),
+            message: None,
+            retries: 2,
+        }]);
+
+        // Not an event the sink wants
+        notifier.notify(Notification::new(
+            "alarm_cleared",
+            Priority::Default,
+            "Alarm cleared",
+            "",
+        ));
+        notifier.notify(
+            Notification::new(
+                "alarm_raised",
+                Priority::High,
+                "Alarm raised",
+                "Current on L1 is 30 A",
+            )
+            .field("meter_id", "main"),
+        );
+        notifier.flush(Duration::from_secs(10)).await;
+
+        let expected = serde_json::json!({
+            "topic": "p1",
+            "title": "main: Alarm raised",
+            "message": "Current on L1 is 30 A",
+            "priority": 4,
+            "tags": ["alarm_raised"],
+        });
+        for _ in 0..2 {
+            let body: serde_json::Value =
+                serde_json::from_str(&bodies.recv().await.unwrap()).unwrap();
+            assert_eq!(body, expected);
+        }
+        assert!(bodies.recv().await.is_none());
+    }
+}
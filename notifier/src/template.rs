@@ -0,0 +1,55 @@
+use crate::Notification;
+
+/// Replaces `{name}` in `template` with the notification's field of that name, or its `{event}`, `{title}`
+/// or `{message}`. Unknown names are left as they are.
+pub fn render(template: &str, notification: &Notification) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let Some(end) = after.find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = &after[..end];
+        let value = match name {
+            "event" => Some(notification.event),
+            "title" => Some(notification.title.as_str()),
+            "message" => Some(notification.message.as_str()),
+            _ => notification
+                .fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.as_str()),
+        };
+        match value {
+            Some(value) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &after[end + 1..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Priority;
+
+    #[test]
+    fn render_fields() {
+        let notification =
+            Notification::new("p1_stalled", Priority::High, "P1 stalled", "No telegrams")
+                .field("meter_id", "garage");
+
+        assert_eq!(
+            render("[{event}] {meter_id}: {message} {unknown} {", &notification),
+            "[p1_stalled] garage: No telegrams {unknown} {"
+        );
+    }
+}
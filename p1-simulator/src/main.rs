@@ -0,0 +1,100 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, SubsecRound, TimeDelta, Utc};
+use clap::{ArgGroup, Parser};
+use meter::Meter;
+use output::Output;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use settings::SimulatorConfig;
+
+mod meter;
+mod output;
+mod telegram;
+
+/// Pretends to be a DSMR 5 smart meter, so the reader can run without one
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+#[command(group(ArgGroup::new("output").required(true)))]
+struct Cli {
+    /// Create a pseudo terminal to use as the reader's SERIAL_PORT, optionally symlinked at LINK
+    #[arg(long, value_name = "LINK", group = "output", num_args = 0..=1)]
+    pty: Option<Option<PathBuf>>,
+    /// Send the telegrams to everyone that connects to this address
+    #[arg(long, value_name = "ADDRESS", group = "output")]
+    tcp: Option<SocketAddr>,
+    /// Append the telegrams to this file
+    #[arg(long, value_name = "FILE", group = "output")]
+    file: Option<PathBuf>,
+    /// Stop after this many telegrams
+    #[arg(short, long)]
+    count: Option<u64>,
+    /// Don't wait between telegrams, the meter's clock runs as fast as the telegrams can be written
+    #[arg(long)]
+    fast: bool,
+    /// The meter time of the first telegram, e.g. 2025-01-15T00:00:00Z. Defaults to now.
+    #[arg(long, value_name = "TIME")]
+    start: Option<DateTime<Utc>>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Cli::parse();
+    let config = SimulatorConfig::load()?;
+
+    let mut output = match (args.pty, args.tcp, args.file) {
+        (Some(link), _, _) => Output::pty(link)?,
+        (_, Some(address), _) => Output::tcp(address)?,
+        (_, _, Some(path)) => Output::file(path)?,
+        _ => unreachable!("clap requires one output"),
+    };
+
+    let mut rng = match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let interval = TimeDelta::from_std(config.interval)?;
+    let max_power_failure = config.max_power_failure.as_secs().max(1);
+    let power_failure_chance = config.power_failure_chance;
+    let corrupt_chance = config.corrupt_chance;
+
+    let start = args.start.unwrap_or_else(Utc::now).trunc_subsecs(0);
+    let mut meter = Meter::new(config, start);
+    let mut elapsed = TimeDelta::zero();
+    let mut deadline = Instant::now();
+    let mut sent = 0;
+
+    while args.count.is_none_or(|count| sent < count) {
+        if rng.gen_bool(power_failure_chance) {
+            let duration = Duration::from_secs(rng.gen_range(1..=max_power_failure));
+            println!("Power failure for {}s", duration.as_secs());
+            if !args.fast {
+                std::thread::sleep(duration);
+                deadline = Instant::now();
+            }
+            meter.power_failure(TimeDelta::from_std(duration)?);
+        }
+
+        meter.step(elapsed, &mut rng);
+        elapsed = interval;
+
+        let mut telegram = telegram::telegram(&meter);
+        if rng.gen_bool(corrupt_chance) {
+            telegram::corrupt(&mut telegram, &mut rng);
+        }
+        output.write(&telegram)?;
+        sent += 1;
+
+        if !args.fast {
+            output.flush()?;
+            deadline += interval.to_std()?;
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    output.flush()?;
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use chrono::{DateTime, Datelike, DurationRound, TimeDelta, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use rand::Rng;
+use settings::{LoadProfile, SimulatorConfig};
+
+/// Power failures shorter than this only increase the power failure count
+const LONG_POWER_FAILURE: TimeDelta = TimeDelta::minutes(3);
+/// The meter only remembers this many long power failures
+const POWER_FAILURE_LOG_SIZE: usize = 10;
+/// Gas meters send a new reading every 5 minutes
+const GAS_INTERVAL: TimeDelta = TimeDelta::minutes(5);
+/// How the household and office loads are spread over the phases
+const PHASE_SHARES: [f64; 3] = [0.5, 0.3, 0.2];
+
+/// The state of a simulated meter, advanced one telegram at a time
+pub struct Meter {
+    pub config: SimulatorConfig,
+    pub time: DateTime<Utc>,
+
+    /// kWh per tariff, low first
+    pub kwh_import: [f64; 2],
+    pub kwh_export: [f64; 2],
+    /// 1 for low, 2 for high
+    pub tariff: u8,
+
+    /// kW
+    pub power_import: [f64; 3],
+    /// kW
+    pub power_export: [f64; 3],
+    /// V
+    pub voltages: [f64; 3],
+    /// A, the meter rounds these to whole amperes
+    pub currents: [u32; 3],
+
+    pub power_failures: u32,
+    pub long_power_failures: u32,
+    /// End time and duration, newest first
+    pub power_failure_log: VecDeque<(DateTime<Utc>, TimeDelta)>,
+    pub voltage_sags: [u32; 3],
+    pub voltage_swells: [u32; 3],
+
+    /// The last reading the gas meter sent: its time and m³
+    pub gas_reading: Option<(DateTime<Utc>, f64)>,
+    gas_total: f64,
+
+    appliance: Option<Appliance>,
+    /// 1 for a clear sky, lower when it's cloudy
+    sunshine: f64,
+}
+
+/// Something that's switched on for a while, like a kettle or a washing machine
+struct Appliance {
+    phase: usize,
+    kw: f64,
+    until: DateTime<Utc>,
+}
+
+impl Meter {
+    pub fn new(config: SimulatorConfig, time: DateTime<Utc>) -> Self {
+        Self {
+            config,
+            time,
+            kwh_import: [4321.987, 3456.789],
+            kwh_export: [567.891, 1234.567],
+            tariff: 1,
+            power_import: [0.0; 3],
+            power_export: [0.0; 3],
+            voltages: [0.0; 3],
+            currents: [0; 3],
+            power_failures: 0,
+            long_power_failures: 0,
+            power_failure_log: VecDeque::new(),
+            voltage_sags: [0; 3],
+            voltage_swells: [0; 3],
+            gas_reading: None,
+            gas_total: 2345.678,
+            appliance: None,
+            sunshine: 1.0,
+        }
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.config.meter_timezone
+    }
+
+    pub fn phases(&self) -> usize {
+        self.config.phases as usize
+    }
+
+    /// Advances the clock by `elapsed` and measures again.
+    ///
+    /// Energy is counted as if the power was the new power for all of `elapsed`.
+    pub fn step(&mut self, elapsed: TimeDelta, rng: &mut impl Rng) {
+        self.time += elapsed;
+        let seconds = elapsed.as_seconds_f64();
+        let hours = seconds / 3600.0;
+        let local_time = self.time.with_timezone(&self.timezone());
+
+        let mut net_power = self.load(seconds, rng);
+        net_power[0] -= self.pv_power(seconds, rng);
+
+        // Nights and weekends are low tariff
+        let weekend = matches!(local_time.weekday(), Weekday::Sat | Weekday::Sun);
+        self.tariff = if weekend || !(7..23).contains(&local_time.hour()) {
+            1
+        } else {
+            2
+        };
+        let tariff = self.tariff as usize - 1;
+
+        for (phase, power) in net_power.into_iter().enumerate().take(self.phases()) {
+            self.power_import[phase] = power.max(0.0);
+            self.power_export[phase] = (-power).max(0.0);
+            self.kwh_import[tariff] += self.power_import[phase] * hours;
+            self.kwh_export[tariff] += self.power_export[phase] * hours;
+
+            // Using power pulls the voltage down, delivering it pushes it up
+            self.voltages[phase] = 231.0 - 1.5 * power + rng.gen_range(-0.8..0.8);
+            self.currents[phase] = (power.abs() * 1000.0 / self.voltages[phase]).round() as u32;
+
+            // About once a week for each
+            if rng.gen_bool((seconds / 604_800.0).min(1.0)) {
+                self.voltage_sags[phase] += 1;
+            }
+            if rng.gen_bool((seconds / 604_800.0).min(1.0)) {
+                self.voltage_swells[phase] += 1;
+            }
+        }
+
+        if self.config.gas {
+            self.gas_total += self.gas_rate(&local_time) * hours;
+
+            let reading_time = self.time.duration_trunc(GAS_INTERVAL).unwrap();
+            if self.gas_reading.is_none_or(|(time, _)| time < reading_time) {
+                self.gas_reading = Some((reading_time, self.gas_total));
+            }
+        }
+    }
+
+    /// The power was out for `duration`, so the clock skips ahead without using any energy
+    pub fn power_failure(&mut self, duration: TimeDelta) {
+        self.time += duration;
+
+        if duration < LONG_POWER_FAILURE {
+            self.power_failures += 1;
+        } else {
+            self.long_power_failures += 1;
+            self.power_failure_log.push_front((self.time, duration));
+            self.power_failure_log.truncate(POWER_FAILURE_LOG_SIZE);
+        }
+    }
+
+    /// kW used per phase
+    fn load(&mut self, seconds: f64, rng: &mut impl Rng) -> [f64; 3] {
+        let config = &self.config;
+        let local_time = self.time.with_timezone(&config.meter_timezone);
+        let hour = local_time.num_seconds_from_midnight() as f64 / 3600.0;
+        let weekend = matches!(local_time.weekday(), Weekday::Sat | Weekday::Sun);
+
+        let busyness = match config.load_profile {
+            LoadProfile::Flat => 0.0,
+            LoadProfile::Household => 0.4 * bump(hour, 7.5, 1.0) + bump(hour, 19.0, 2.0),
+            LoadProfile::Office if weekend => 0.0,
+            LoadProfile::Office => {
+                smoothstep(hour, 7.0, 9.0) * (1.0 - smoothstep(hour, 17.0, 19.0))
+            }
+        };
+        let total = config.base_load_kw + config.peak_load_kw * busyness;
+        let noise = rng.gen_range(0.95..1.05);
+
+        let mut load = [0.0; 3];
+        if config.phases == 1 {
+            load[0] = total * noise;
+        } else {
+            for (load, share) in load.iter_mut().zip(PHASE_SHARES) {
+                *load = total * share * noise;
+            }
+        }
+
+        if config.load_profile == LoadProfile::Household {
+            if self
+                .appliance
+                .as_ref()
+                .is_some_and(|a| a.until <= self.time)
+            {
+                self.appliance = None;
+            }
+            // Something switches on about twice an hour when people are busy
+            let chance = (busyness + 0.2) / 1800.0 * seconds;
+            if self.appliance.is_none() && rng.gen_bool(chance.min(1.0)) {
+                self.appliance = Some(Appliance {
+                    phase: rng.gen_range(0..self.config.phases as usize),
+                    kw: rng.gen_range(1.0..2.5),
+                    until: self.time + TimeDelta::seconds(rng.gen_range(60..1800)),
+                });
+            }
+            if let Some(appliance) = &self.appliance {
+                load[appliance.phase] += appliance.kw;
+            }
+        }
+
+        load
+    }
+
+    /// kW the solar panels deliver, roughly following the sun in the Netherlands
+    fn pv_power(&mut self, seconds: f64, rng: &mut impl Rng) -> f64 {
+        if self.config.pv_peak_kw <= 0.0 {
+            return 0.0;
+        }
+
+        // Clouds drift in and out
+        self.sunshine =
+            (self.sunshine + rng.gen_range(-0.01..0.01) * seconds.sqrt()).clamp(0.2, 1.0);
+
+        let year_angle = 2.0 * PI * (self.time.ordinal() as f64 - 172.0) / 365.25;
+        let day_length = 12.2 + 4.3 * year_angle.cos();
+        let solar_noon = 11.7;
+        let hour = self.time.num_seconds_from_midnight() as f64 / 3600.0;
+        let sunrise = solar_noon - day_length / 2.0;
+        if hour < sunrise || hour > sunrise + day_length {
+            return 0.0;
+        }
+
+        let elevation = (PI * (hour - sunrise) / day_length).sin();
+        // The sun is lower in winter, so even noon is darker
+        let season = 0.3 + 0.7 * ((day_length - 7.9) / 8.6).clamp(0.0, 1.0);
+        self.config.pv_peak_kw * elevation.powf(1.5) * season * self.sunshine
+    }
+
+    /// m³ per hour, mostly heating
+    fn gas_rate(&self, local_time: &DateTime<Tz>) -> f64 {
+        let year_angle = 2.0 * PI * (local_time.ordinal() as f64 - 15.0) / 365.25;
+        let winter = (1.0 + year_angle.cos()) / 2.0;
+        let thermostat = if (6..23).contains(&local_time.hour()) {
+            1.0
+        } else {
+            0.3
+        };
+        0.03 + 0.35 * winter * thermostat
+    }
+}
+
+/// A bell curve around `center` that is 1 at its peak
+fn bump(x: f64, center: f64, width: f64) -> f64 {
+    (-((x - center) / width).powi(2) / 2.0).exp()
+}
+
+/// Goes smoothly from 0 at `from` to 1 at `to`
+fn smoothstep(x: f64, from: f64, to: f64) -> f64 {
+    let t = ((x - from) / (to - from)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
@@ -0,0 +1,121 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    time::Duration,
+};
+
+use serialport::{SerialPort, TTYPort};
+
+/// Where the telegrams go
+pub enum Output {
+    /// A pseudo terminal, which the reader can open like the serial port of a real P1 cable
+    Pty {
+        master: TTYPort,
+        /// Kept open so the terminal stays around while the reader reconnects
+        _slave: TTYPort,
+        link: Option<PathBuf>,
+    },
+    /// Every client that connects gets the telegrams, like a serial to network bridge
+    Tcp {
+        listener: TcpListener,
+        clients: Vec<(SocketAddr, TcpStream)>,
+    },
+    File(BufWriter<File>),
+}
+
+impl Output {
+    /// Creates a pseudo terminal, and symlinks it at `link` if that's set
+    pub fn pty(link: Option<PathBuf>) -> io::Result<Self> {
+        let (master, slave) = TTYPort::pair()?;
+        let name = slave.name().unwrap();
+
+        if let Some(link) = &link {
+            // Only replace what an earlier run left behind
+            if link.is_symlink() {
+                std::fs::remove_file(link)?;
+            }
+            std::os::unix::fs::symlink(&name, link)?;
+            println!("Writing telegrams to {name}, linked at {}", link.display());
+        } else {
+            println!("Writing telegrams to {name}");
+        }
+
+        Ok(Self::Pty {
+            master,
+            _slave: slave,
+            link,
+        })
+    }
+
+    pub fn tcp(address: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        println!("Writing telegrams to everyone connecting to {address}");
+
+        Ok(Self::Tcp {
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    pub fn file(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        println!("Writing telegrams to {}", path.display());
+
+        Ok(Self::File(BufWriter::new(file)))
+    }
+
+    pub fn write(&mut self, telegram: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Pty { master, .. } => match master.write_all(telegram) {
+                // Nobody is reading and the terminal's buffer is full. A real meter doesn't care either.
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(()),
+                result => result,
+            },
+            Self::Tcp { listener, clients } => {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, address)) => {
+                            println!("{address} connected");
+                            stream.set_nonblocking(false)?;
+                            stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+                            clients.push((address, stream));
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                clients.retain_mut(|(address, stream)| match stream.write_all(telegram) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        println!("{address} disconnected: {e}");
+                        false
+                    }
+                });
+                Ok(())
+            }
+            Self::File(file) => file.write_all(telegram),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Self::Pty {
+            link: Some(link), ..
+        } = self
+        {
+            let _ = std::fs::remove_file(link);
+        }
+    }
+}
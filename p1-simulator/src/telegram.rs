@@ -0,0 +1,287 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::{OffsetComponents, Tz};
+use rand::Rng;
+
+use crate::meter::Meter;
+
+const HEADER: &str = "/SIM5\\2P1-SIMULATOR";
+const GAS_EQUIPMENT_IDENTIFIER: &str = "SIMGAS0000000001";
+/// The M-Bus device type of a gas meter
+const GAS_DEVICE_TYPE: u8 = 3;
+/// The OBIS codes of the values per phase
+const PHASE_GROUPS: [u8; 3] = [32, 52, 72];
+
+/// Formats the meter's current state as a DSMR 5 telegram, including the CRC
+pub fn telegram(meter: &Meter) -> Vec<u8> {
+    let timezone = meter.timezone();
+    let phases = 0..meter.phases();
+    let mut t = String::new();
+
+    // Writing to a String can't fail
+    let mut line = |line: std::fmt::Arguments| {
+        t.write_fmt(line).unwrap();
+        t.push_str("\r\n");
+    };
+
+    line(format_args!("{HEADER}"));
+    line(format_args!(""));
+    line(format_args!("1-3:0.2.8(50)"));
+    line(format_args!(
+        "0-0:1.0.0({})",
+        timestamp(meter.time, timezone)
+    ));
+    line(format_args!(
+        "0-0:96.1.1({})",
+        hex(&meter.config.equipment_identifier)
+    ));
+    for tariff in 0..2 {
+        line(format_args!(
+            "1-0:1.8.{}({:010.3}*kWh)",
+            tariff + 1,
+            meter.kwh_import[tariff]
+        ));
+    }
+    for tariff in 0..2 {
+        line(format_args!(
+            "1-0:2.8.{}({:010.3}*kWh)",
+            tariff + 1,
+            meter.kwh_export[tariff]
+        ));
+    }
+    line(format_args!("0-0:96.14.0({:04})", meter.tariff));
+    line(format_args!(
+        "1-0:1.7.0({:06.3}*kW)",
+        meter.power_import.iter().sum::<f64>()
+    ));
+    line(format_args!(
+        "1-0:2.7.0({:06.3}*kW)",
+        meter.power_export.iter().sum::<f64>()
+    ));
+    line(format_args!("0-0:96.7.21({:05})", meter.power_failures));
+    line(format_args!("0-0:96.7.9({:05})", meter.long_power_failures));
+
+    let mut log = format!(
+        "1-0:99.97.0({})(0-0:96.7.19)",
+        meter.power_failure_log.len()
+    );
+    for (end_time, duration) in &meter.power_failure_log {
+        write!(
+            log,
+            "({})({:010}*s)",
+            timestamp(*end_time, timezone),
+            duration.num_seconds()
+        )
+        .unwrap();
+    }
+    line(format_args!("{log}"));
+
+    for phase in phases.clone() {
+        line(format_args!(
+            "1-0:{}.32.0({:05})",
+            PHASE_GROUPS[phase], meter.voltage_sags[phase]
+        ));
+    }
+    for phase in phases.clone() {
+        line(format_args!(
+            "1-0:{}.36.0({:05})",
+            PHASE_GROUPS[phase], meter.voltage_swells[phase]
+        ));
+    }
+    line(format_args!("0-0:96.13.0()"));
+    for phase in phases.clone() {
+        line(format_args!(
+            "1-0:{}.7.0({:05.1}*V)",
+            PHASE_GROUPS[phase], meter.voltages[phase]
+        ));
+    }
+    for phase in phases.clone() {
+        line(format_args!(
+            "1-0:{}.7.0({:03}*A)",
+            PHASE_GROUPS[phase] - 1,
+            meter.currents[phase]
+        ));
+    }
+    for phase in phases.clone() {
+        line(format_args!(
+            "1-0:{}.7.0({:06.3}*kW)",
+            PHASE_GROUPS[phase] - 11,
+            meter.power_import[phase]
+        ));
+    }
+    for phase in phases {
+        line(format_args!(
+            "1-0:{}.7.0({:06.3}*kW)",
+            PHASE_GROUPS[phase] - 10,
+            meter.power_export[phase]
+        ));
+    }
+
+    if let Some((time, value)) = meter.gas_reading {
+        line(format_args!("0-1:24.1.0({GAS_DEVICE_TYPE:03})"));
+        line(format_args!(
+            "0-1:96.1.0({})",
+            hex(GAS_EQUIPMENT_IDENTIFIER)
+        ));
+        line(format_args!(
+            "0-1:24.2.1({})({value:09.3}*m3)",
+            timestamp(time, timezone)
+        ));
+    }
+
+    // The CRC covers everything from the / up to and including the !
+    t.push('!');
+    let crc = crc16::State::<crc16::ARC>::calculate(t.as_bytes());
+    write!(t, "{crc:04X}\r\n").unwrap();
+
+    t.into_bytes()
+}
+
+/// Damages a telegram by flipping one bit between the header and the CRC, like a noisy line would
+pub fn corrupt(telegram: &mut [u8], rng: &mut impl Rng) {
+    let end = telegram.iter().position(|&b| b == b'!').unwrap();
+    let position = rng.gen_range(1..end);
+    telegram[position] ^= 1 << rng.gen_range(0..7);
+}
+
+/// `YYMMDDhhmmssX` in local time, where X is S during summer time and W otherwise
+fn timestamp(time: DateTime<Utc>, timezone: Tz) -> String {
+    let local_time = time.with_timezone(&timezone);
+    let season = if local_time.offset().dst_offset() == TimeDelta::zero() {
+        'W'
+    } else {
+        'S'
+    };
+    format!("{}{season}", local_time.format("%y%m%d%H%M%S"))
+}
+
+fn hex(text: &str) -> String {
+    text.bytes().map(|b| format!("{b:02X}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use rand::{SeedableRng, rngs::StdRng};
+    use settings::{LoadProfile, SimulatorConfig};
+
+    use super::*;
+
+    fn config(phases: u8) -> SimulatorConfig {
+        SimulatorConfig {
+            interval: std::time::Duration::from_secs(1),
+            meter_timezone: chrono_tz::Europe::Amsterdam,
+            equipment_identifier: "SIM0000000000001".into(),
+            phases,
+            load_profile: LoadProfile::Household,
+            base_load_kw: 0.3,
+            peak_load_kw: 2.5,
+            pv_peak_kw: 4.0,
+            gas: true,
+            power_failure_chance: 0.0,
+            max_power_failure: std::time::Duration::from_secs(600),
+            corrupt_chance: 0.0,
+            seed: None,
+        }
+    }
+
+    fn parse(telegram: &[u8]) -> Result<p1::TelegramData, dsmr5::Error> {
+        let readout = dsmr5::Reader::new(telegram.iter().map(|&byte| Ok::<_, ()>(byte)))
+            .next()
+            .unwrap()
+            .unwrap();
+        let parsed = readout.to_telegram()?;
+        Ok(p1::telegram_to_data(parsed, &readout, chrono_tz::Europe::Amsterdam).unwrap())
+    }
+
+    #[test]
+    fn three_phases_with_pv_and_gas() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // A summer afternoon, so the panels deliver and the meter is on summer time
+        let start = Utc.with_ymd_and_hms(2025, 6, 18, 11, 59, 0).unwrap();
+        let mut meter = Meter::new(config(3), start);
+        for _ in 0..120 {
+            meter.step(TimeDelta::seconds(1), &mut rng);
+        }
+
+        let data = parse(&telegram(&meter)).unwrap();
+
+        let electricity_data = &data.electricity_data;
+        assert_eq!(electricity_data.time, meter.time);
+        assert_eq!(
+            electricity_data.kwh_import_total_tarif_high,
+            (meter.kwh_import[1] * 1000.0).round() as f32 / 1000.0
+        );
+        assert!(electricity_data.active_powers_export[0] > 0.0);
+        for phase in 0..3 {
+            assert!((electricity_data.voltages[phase] as f64 - meter.voltages[phase]).abs() < 0.06);
+            assert_eq!(
+                electricity_data.current[phase],
+                meter.currents[phase] as f32
+            );
+        }
+        assert_eq!(data.status.equipment_identifier, "SIM0000000000001");
+        assert_eq!(data.status.tariff_indicator, 2);
+
+        let gas = data.slave_data[0].unwrap();
+        assert_eq!(
+            gas.time,
+            Utc.with_ymd_and_hms(2025, 6, 18, 12, 0, 0).unwrap()
+        );
+        assert_eq!(
+            data.slave_devices[0].as_ref().unwrap().equipment_identifier,
+            GAS_EQUIPMENT_IDENTIFIER
+        );
+    }
+
+    #[test]
+    fn single_phase_at_night() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let start = Utc.with_ymd_and_hms(2025, 1, 15, 1, 0, 0).unwrap();
+        let mut meter = Meter::new(config(1), start);
+        meter.step(TimeDelta::zero(), &mut rng);
+
+        let telegram = telegram(&meter);
+        assert!(!String::from_utf8_lossy(&telegram).contains("1-0:52.7.0"));
+
+        let data = parse(&telegram).unwrap();
+        assert_eq!(data.electricity_data.active_powers_export, [0.0; 3]);
+        assert!(data.electricity_data.active_powers_import[0] > 0.0);
+        assert_eq!(data.electricity_data.voltages[1], 0.0);
+        assert_eq!(data.status.tariff_indicator, 1);
+    }
+
+    #[test]
+    fn power_failures() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let start = Utc.with_ymd_and_hms(2025, 10, 26, 0, 0, 0).unwrap();
+        let mut meter = Meter::new(config(3), start);
+        meter.power_failure(TimeDelta::seconds(30));
+        meter.power_failure(TimeDelta::seconds(600));
+        meter.step(TimeDelta::seconds(1), &mut rng);
+
+        let data = parse(&telegram(&meter)).unwrap();
+        assert_eq!(data.status.power_failures, 1);
+        assert_eq!(data.status.long_power_failures, 1);
+        assert_eq!(data.power_failure_events.len(), 1);
+        assert_eq!(
+            data.power_failure_events[0].end_time,
+            start + TimeDelta::seconds(630)
+        );
+        assert_eq!(data.power_failure_events[0].duration_seconds, 600);
+    }
+
+    #[test]
+    fn corrupted_telegrams() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut meter = Meter::new(config(3), Utc::now());
+        meter.step(TimeDelta::zero(), &mut rng);
+
+        for _ in 0..20 {
+            let mut telegram = telegram(&meter);
+            corrupt(&mut telegram, &mut rng);
+            assert!(parse(&telegram).is_err());
+        }
+    }
+}
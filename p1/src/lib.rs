@@ -0,0 +1,145 @@
+//! The data a DSMR 5 smart meter sends over its P1 port, and the conversion from raw telegrams.
+//!
+//! All types can be serialized, so the same definitions are used for the spool, the HTTP API, MQTT and
+//! exports of the database.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+mod slave_device;
+mod telegram;
+mod timestamp;
+
+pub use slave_device::SlaveDevice;
+pub use telegram::telegram_to_data;
+pub use timestamp::tst_to_utc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid meter time: {0}")]
+    InvalidTime(String),
+    #[error("Invalid object: {0}")]
+    InvalidObject(String),
+    #[error("Invalid text: {0}")]
+    InvalidText(#[from] std::str::Utf8Error),
+    #[error("Invalid text: {0}")]
+    InvalidString(#[from] std::string::FromUtf8Error),
+    #[error("Invalid number: {0}")]
+    InvalidNumber(#[from] std::num::ParseIntError),
+}
+
+/// Everything in one telegram
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TelegramData {
+    pub electricity_data: ElectricityData,
+    pub slave_data: [Option<SlaveData>; 4],
+    pub slave_devices: [Option<SlaveDevice>; 4],
+    pub status: MeterStatus,
+    pub power_failure_events: Vec<PowerFailureEvent>,
+}
+
+impl TelegramData {
+    pub fn slave_readings(&self) -> impl Iterator<Item = SlaveReading<'_>> {
+        self.slave_data
+            .iter()
+            .enumerate()
+            .filter_map(|(channel, slave_data)| {
+                let device = self.slave_devices[channel].as_ref();
+                Some(SlaveReading {
+                    channel,
+                    data: slave_data.as_ref()?,
+                    equipment_identifier: device.map(|device| &*device.equipment_identifier),
+                    name: device.map(|device| device.name()),
+                    unit: device.map(|device| device.unit()),
+                })
+            })
+    }
+}
+
+/// Exports of the database don't have everything, so those fields default to zero
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ElectricityData {
+    /// The time reported by the meter
+    pub time: chrono::DateTime<Utc>,
+    /// The time we received the telegram
+    #[serde(default)]
+    pub received_time: chrono::DateTime<Utc>,
+
+    pub kwh_import_total_tarif_low: f32,
+    pub kwh_import_total_tarif_high: f32,
+    pub kwh_export_total_tarif_low: f32,
+    pub kwh_export_total_tarif_high: f32,
+
+    #[serde(default)]
+    pub voltages: [f32; 3],
+    #[serde(default)]
+    pub current: [f32; 3],
+    #[serde(default)]
+    pub active_powers_import: [f32; 3],
+    #[serde(default)]
+    pub active_powers_export: [f32; 3],
+
+    /// kW, the average import power of the current quarter-hour so far (1-0:1.4.0). Only Belgian e-MUCS
+    /// meters send it.
+    #[serde(default)]
+    pub average_demand: Option<f32>,
+    /// The highest quarter-hour average import power of this month (1-0:1.6.0). Only Belgian e-MUCS meters
+    /// send it.
+    #[serde(default)]
+    pub max_demand_month: Option<MaxDemand>,
+}
+
+impl ElectricityData {
+    /// kWh balance, positive for import, negative for export
+    pub fn kwh_balance(&self) -> f32 {
+        self.kwh_import_total_tarif_low + self.kwh_import_total_tarif_high
+            - self.kwh_export_total_tarif_high
+            - self.kwh_export_total_tarif_low
+    }
+}
+
+/// A peak in the quarter-hour average import power, which the Flemish capacity tariff bills
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaxDemand {
+    /// When the meter registered the peak
+    pub time: chrono::DateTime<Utc>,
+    pub kw: f32,
+}
+
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub struct SlaveData {
+    pub time: chrono::DateTime<Utc>,
+    pub value: f32,
+}
+
+/// A slave reading along with what kind of device it came from
+#[derive(Debug, Serialize)]
+pub struct SlaveReading<'a> {
+    pub channel: usize,
+    #[serde(flatten)]
+    pub data: &'a SlaveData,
+    pub equipment_identifier: Option<&'a str>,
+    pub name: Option<&'a str>,
+    pub unit: Option<&'a str>,
+}
+
+/// Slow changing meter state. Only stored when something changes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MeterStatus {
+    pub equipment_identifier: String,
+    pub tariff_indicator: i16,
+
+    pub power_failures: i32,
+    pub long_power_failures: i32,
+
+    pub voltage_sags: [i32; 3],
+    pub voltage_swells: [i32; 3],
+
+    pub text_message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PowerFailureEvent {
+    pub end_time: chrono::DateTime<Utc>,
+    pub duration_seconds: i32,
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+/// A gas, water or heat meter connected to the P1 meter over M-Bus
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SlaveDevice {
+    /// The M-Bus device type as defined by EN 13757-3
+    pub device_type: i16,
+    pub equipment_identifier: String,
+}
+
+impl SlaveDevice {
+    /// Default display name. This can be changed in the database.
+    pub fn name(&self) -> &'static str {
+        match self.device_type {
+            0x02 => "Electricity",
+            0x03 => "Gas",
+            0x04 | 0x0C => "Heat",
+            0x06 => "Warm water",
+            0x07 => "Water",
+            0x0A | 0x0B => "Cooling",
+            0x0D => "Heat/cooling",
+            0x16 => "Cold water",
+            _ => "Unknown",
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self.device_type {
+            0x02 => "kWh",
+            0x03 | 0x06 | 0x07 | 0x16 => "m³",
+            0x04 | 0x0A | 0x0B | 0x0C | 0x0D => "GJ",
+            _ => "",
+        }
+    }
+}
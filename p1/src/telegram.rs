@@ -0,0 +1,506 @@
+use chrono_tz::Tz;
+use dsmr5::{
+    Readout, Tariff, Telegram,
+    types::{OctetString, TST},
+};
+
+use crate::{
+    ElectricityData, Error, MaxDemand, MeterStatus, PowerFailureEvent, SlaveData, SlaveDevice,
+    TelegramData, tst_to_utc,
+};
+
+/// Converts a telegram to our own types. `readout` is the raw telegram, which is needed for the objects the
+/// dsmr5 crate doesn't parse.
+///
+/// The meter reports local times in `timezone`.
+pub fn telegram_to_data(
+    telegram: Telegram,
+    readout: &Readout,
+    timezone: Tz,
+) -> Result<TelegramData, Error> {
+    let received_time = chrono::Utc::now();
+    let mut electricity_data = ElectricityData {
+        // Overwritten by the meter's own timestamp if the telegram has one
+        time: received_time,
+        received_time,
+        ..Default::default()
+    };
+    let mut slave_data = [None; 4];
+    let mut slave_devices: [Option<SlaveDevice>; 4] = Default::default();
+    let mut status = MeterStatus::default();
+
+    for obj in telegram.objects() {
+        match obj {
+            Ok(obj) => match obj {
+                dsmr5::OBIS::DateTime(ref timestamp) => {
+                    electricity_data.time = tst_to_utc(timestamp, timezone)?;
+                }
+                dsmr5::OBIS::EquipmentIdentifier(ref val) => {
+                    status.equipment_identifier = octet_string_to_string(val)?;
+                }
+                dsmr5::OBIS::TariffIndicator(ref val) => {
+                    status.tariff_indicator = val
+                        .as_octets()
+                        .try_fold(0, |acc, octet| octet.map(|octet| (acc << 8) | octet as i16))
+                        .map_err(|e| Error::InvalidObject(format!("{e:?}")))?;
+                }
+                dsmr5::OBIS::PowerFailures(ref val) => {
+                    status.power_failures = val.0 as i32;
+                }
+                dsmr5::OBIS::LongPowerFailures(ref val) => {
+                    status.long_power_failures = val.0 as i32;
+                }
+                dsmr5::OBIS::VoltageSags(line, ref val) => {
+                    status.voltage_sags[line as usize] = val.0 as i32;
+                }
+                dsmr5::OBIS::VoltageSwells(line, ref val) => {
+                    status.voltage_swells[line as usize] = val.0 as i32;
+                }
+                dsmr5::OBIS::MeterReadingTo(Tariff::Tariff1, ref val) => {
+                    electricity_data.kwh_import_total_tarif_low = f64::from(val) as f32;
+                }
+                dsmr5::OBIS::MeterReadingTo(Tariff::Tariff2, ref val) => {
+                    electricity_data.kwh_import_total_tarif_high = f64::from(val) as f32;
+                }
+                dsmr5::OBIS::MeterReadingBy(Tariff::Tariff1, ref val) => {
+                    electricity_data.kwh_export_total_tarif_low = f64::from(val) as f32;
+                }
+                dsmr5::OBIS::MeterReadingBy(Tariff::Tariff2, ref val) => {
+                    electricity_data.kwh_export_total_tarif_high = f64::from(val) as f32;
+                }
+                dsmr5::OBIS::InstantaneousVoltage(line, ref val) => {
+                    electricity_data.voltages[line as usize] = f64::from(val) as f32;
+                }
+                dsmr5::OBIS::InstantaneousCurrent(line, ref val) => {
+                    electricity_data.current[line as usize] = val.0 as f32;
+                }
+                dsmr5::OBIS::InstantaneousActivePowerPlus(line, ref val) => {
+                    electricity_data.active_powers_import[line as usize] = f64::from(val) as f32;
+                }
+                dsmr5::OBIS::InstantaneousActivePowerNeg(line, ref val) => {
+                    electricity_data.active_powers_export[line as usize] = f64::from(val) as f32;
+                }
+                dsmr5::OBIS::SlaveDeviceType(s, Some(ref val)) => {
+                    slave_devices[s as usize]
+                        .get_or_insert_with(Default::default)
+                        .device_type = val.0 as i16;
+                }
+                dsmr5::OBIS::SlaveEquipmentIdentifier(s, ref val) => {
+                    slave_devices[s as usize]
+                        .get_or_insert_with(Default::default)
+                        .equipment_identifier = octet_string_to_string(val)?;
+                }
+                dsmr5::OBIS::SlaveMeterReading(s, timestamp, Some(ref val)) => {
+                    slave_data[s as usize] = Some(SlaveData {
+                        time: tst_to_utc(&timestamp, timezone)?,
+                        value: f64::from(val) as f32,
+                    });
+                }
+                _ => {}
+            },
+            Err(e) => tracing::debug!("Obj error: {e:?}"),
+        }
+    }
+
+    let mut power_failure_events = Vec::new();
+
+    // The dsmr5 crate recognizes the first two objects but doesn't give us their contents, and doesn't know
+    // the e-MUCS ones at all
+    let raw_telegram = std::str::from_utf8(&readout.buffer)?.trim_end_matches('\0');
+    for line in raw_telegram.lines() {
+        if let Some(body) = line.strip_prefix("1-0:99.97.0") {
+            power_failure_events = parse_power_failure_event_log(body, timezone)?;
+        } else if let Some(body) = line.strip_prefix("0-0:96.13.0") {
+            status.text_message = parse_text_message(body)?;
+        } else if let Some(body) = line.strip_prefix("1-0:1.4.0") {
+            electricity_data.average_demand = Some(parse_kw(body)?);
+        } else if let Some(body) = line.strip_prefix("1-0:1.6.0") {
+            electricity_data.max_demand_month = Some(parse_max_demand(body, timezone)?);
+        } else if let Some((channel, body)) = line
+            .strip_prefix("0-")
+            .and_then(|line| line.split_once(":96.1.1"))
+        {
+            // e-MUCS meters have the M-Bus equipment identifier here instead of in 0-n:96.1.0
+            if let Ok(channel @ 1..=4) = channel.parse::<usize>()
+                && let Some(slave_device) = &mut slave_devices[channel - 1]
+            {
+                slave_device.equipment_identifier = parse_text_message(body)?;
+            }
+        }
+    }
+
+    // Devices are told apart by their equipment identifier, so one without is useless
+    for slave_device in &mut slave_devices {
+        if slave_device
+            .as_ref()
+            .is_some_and(|slave_device| slave_device.equipment_identifier.is_empty())
+        {
+            *slave_device = None;
+        }
+    }
+
+    Ok(TelegramData {
+        electricity_data,
+        slave_data,
+        slave_devices,
+        status,
+        power_failure_events,
+    })
+}
+
+fn octet_string_to_string(val: &OctetString) -> Result<String, Error> {
+    let octets = val
+        .as_octets()
+        .collect::<Result<_, _>>()
+        .map_err(|e| Error::InvalidObject(format!("{e:?}")))?;
+
+    Ok(String::from_utf8(octets)?)
+}
+
+/// Parses a body like `(2)(0-0:96.7.19)(101208152415W)(0000000240*s)(101208151004W)(0000000301*s)`
+fn parse_power_failure_event_log(
+    body: &str,
+    timezone: Tz,
+) -> Result<Vec<PowerFailureEvent>, Error> {
+    let mut values = body.split_inclusive(')').skip(2);
+    let mut events = Vec::new();
+
+    while let (Some(end_time), Some(duration)) = (values.next(), values.next()) {
+        let end_time = TST::parse(end_time).map_err(|e| Error::InvalidObject(format!("{e:?}")))?;
+
+        // Unused slots in the log are filled with a timestamp in the year 2000
+        if end_time.year == 0 {
+            continue;
+        }
+
+        let duration_seconds = duration
+            .trim_start_matches('(')
+            .trim_end_matches("*s)")
+            .parse()?;
+
+        events.push(PowerFailureEvent {
+            end_time: tst_to_utc(&end_time, timezone)?,
+            duration_seconds,
+        });
+    }
+
+    Ok(events)
+}
+
+/// Parses a body like `(02.351*kW)`
+fn parse_kw(body: &str) -> Result<f32, Error> {
+    body.trim_start_matches('(')
+        .trim_end_matches(')')
+        .strip_suffix("*kW")
+        .and_then(|kw| kw.parse().ok())
+        .ok_or_else(|| Error::InvalidObject(format!("Not a kW value: {body}")))
+}
+
+/// Parses a body like `(200509134558S)(02.589*kW)`
+fn parse_max_demand(body: &str, timezone: Tz) -> Result<MaxDemand, Error> {
+    let (time, kw) = body
+        .split_once(")(")
+        .ok_or_else(|| Error::InvalidObject(format!("Not a maximum demand: {body}")))?;
+    let time =
+        TST::parse(&format!("{time})")).map_err(|e| Error::InvalidObject(format!("{e:?}")))?;
+
+    Ok(MaxDemand {
+        time: tst_to_utc(&time, timezone)?,
+        kw: parse_kw(kw)?,
+    })
+}
+
+/// Parses a hex encoded body like `(48656C6C6F)`
+fn parse_text_message(body: &str) -> Result<String, Error> {
+    let hex = body.trim_start_matches('(').trim_end_matches(')');
+
+    // Slicing the body would panic on anything that isn't ASCII
+    let bytes = hex
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some(hex_digit(*high)? << 4 | hex_digit(*low)?),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| Error::InvalidObject(format!("Not a hex encoded text: {body}")))?;
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    /// Based on the example telegram in the DSMR 5.0.2 P1 companion standard, three phases and a gas meter
+    const DSMR5_SPEC: &[u8] = include_bytes!("../testdata/dsmr5_spec.txt");
+    /// A single phase Iskra meter with a gas meter
+    const ISK: &[u8] = include_bytes!("../testdata/isk.txt");
+    /// A three phase Kaifa meter without voltages and with unused power failure log slots
+    const KAIFA: &[u8] = include_bytes!("../testdata/kaifa.txt");
+    /// A Belgian e-MUCS meter, with the capacity tariff objects
+    const FLUVIUS: &[u8] = include_bytes!("../testdata/fluvius.txt");
+
+    fn readout(sample: &[u8]) -> Readout {
+        dsmr5::Reader::new(sample.iter().map(|&byte| Ok::<_, ()>(byte)))
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn parse(sample: &[u8]) -> TelegramData {
+        let readout = readout(sample);
+        let telegram = readout.to_telegram().unwrap();
+        telegram_to_data(telegram, &readout, chrono_tz::Europe::Amsterdam).unwrap()
+    }
+
+    fn utc(
+        year: i32,
+        month: u32,
+        day: u32,
+        hour: u32,
+        minute: u32,
+        second: u32,
+    ) -> chrono::DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, second)
+            .unwrap()
+    }
+
+    #[test]
+    fn three_phase_telegram() {
+        let data = parse(DSMR5_SPEC);
+
+        let electricity_data = &data.electricity_data;
+        assert_eq!(electricity_data.time, utc(2010, 12, 9, 10, 30, 20));
+        assert_eq!(electricity_data.kwh_import_total_tarif_low, 123_456.79);
+        assert_eq!(electricity_data.kwh_import_total_tarif_high, 123_456.79);
+        assert_eq!(electricity_data.kwh_export_total_tarif_low, 123_456.79);
+        assert_eq!(electricity_data.kwh_export_total_tarif_high, 123_456.79);
+        assert_eq!(electricity_data.voltages, [220.1, 220.2, 220.3]);
+        assert_eq!(electricity_data.current, [1.0, 2.0, 3.0]);
+        assert_eq!(electricity_data.active_powers_import, [1.111, 2.222, 3.333]);
+        assert_eq!(electricity_data.active_powers_export, [4.444, 5.555, 6.666]);
+
+        assert_eq!(
+            data.status,
+            MeterStatus {
+                equipment_identifier: "K8EG004046395507".into(),
+                tariff_indicator: 2,
+                power_failures: 4,
+                long_power_failures: 2,
+                voltage_sags: [2, 1, 0],
+                voltage_swells: [0, 3, 0],
+                text_message: "0123456789:;<=>?".repeat(5),
+            }
+        );
+
+        assert_eq!(
+            data.power_failure_events,
+            [
+                PowerFailureEvent {
+                    end_time: utc(2010, 12, 8, 14, 24, 15),
+                    duration_seconds: 240,
+                },
+                PowerFailureEvent {
+                    end_time: utc(2010, 12, 8, 14, 10, 4),
+                    duration_seconds: 301,
+                },
+            ]
+        );
+
+        assert_eq!(
+            data.slave_devices[0],
+            Some(SlaveDevice {
+                device_type: 3,
+                equipment_identifier: "2222ABCD123456789".into(),
+            })
+        );
+        let gas = data.slave_data[0].unwrap();
+        assert_eq!(gas.time, utc(2010, 12, 9, 10, 25, 0));
+        assert_eq!(gas.value, 12785.123);
+        assert!(data.slave_data[1..].iter().all(Option::is_none));
+
+        let readings = data.slave_readings().collect::<Vec<_>>();
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].name, Some("Gas"));
+        assert_eq!(readings[0].unit, Some("m³"));
+    }
+
+    #[test]
+    fn single_phase_telegram() {
+        let data = parse(ISK);
+
+        let electricity_data = &data.electricity_data;
+        assert_eq!(electricity_data.time, utc(2019, 3, 20, 17, 14, 3));
+        assert_eq!(electricity_data.kwh_import_total_tarif_low, 576.239);
+        assert_eq!(electricity_data.kwh_import_total_tarif_high, 465.162);
+        assert_eq!(electricity_data.voltages, [236.1, 0.0, 0.0]);
+        assert_eq!(electricity_data.current, [1.0, 0.0, 0.0]);
+        assert_eq!(electricity_data.active_powers_import, [0.193, 0.0, 0.0]);
+        assert_eq!(electricity_data.kwh_balance(), 576.239 + 465.162);
+
+        assert_eq!(data.status.equipment_identifier, "E0043007052870318");
+        assert_eq!(data.status.voltage_sags, [6, 0, 0]);
+        assert_eq!(data.status.text_message, "");
+
+        assert_eq!(data.power_failure_events.len(), 6);
+        assert_eq!(
+            data.power_failure_events[0],
+            PowerFailureEvent {
+                end_time: utc(2019, 2, 1, 22, 52, 31),
+                duration_seconds: 3231,
+            }
+        );
+
+        let gas = data.slave_data[0].unwrap();
+        assert_eq!(gas.time, utc(2019, 3, 20, 17, 10, 3));
+        assert_eq!(gas.value, 304.089);
+    }
+
+    #[test]
+    fn summer_time_and_unused_log_slots() {
+        let data = parse(KAIFA);
+
+        // S means summer time, two hours ahead of UTC
+        assert_eq!(data.electricity_data.time, utc(2022, 9, 1, 13, 22, 1));
+        assert_eq!(data.electricity_data.voltages, [0.0; 3]);
+        assert_eq!(
+            data.electricity_data.active_powers_import,
+            [0.254, 0.083, 0.0]
+        );
+
+        // The last four slots have a timestamp in the year 2000
+        assert_eq!(
+            data.power_failure_events,
+            [
+                PowerFailureEvent {
+                    end_time: utc(2022, 8, 26, 12, 58, 25),
+                    duration_seconds: 4982,
+                },
+                PowerFailureEvent {
+                    end_time: utc(2018, 12, 18, 15, 22, 32),
+                    duration_seconds: 817,
+                },
+                PowerFailureEvent {
+                    end_time: utc(2016, 10, 25, 10, 25, 18),
+                    duration_seconds: 2667,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn capacity_tariff_objects() {
+        let data = parse(FLUVIUS);
+
+        let electricity_data = &data.electricity_data;
+        assert_eq!(electricity_data.time, utc(2020, 5, 12, 11, 54, 9));
+        assert_eq!(electricity_data.kwh_import_total_tarif_high, 15.758);
+        assert_eq!(electricity_data.average_demand, Some(2.351));
+        assert_eq!(
+            electricity_data.max_demand_month,
+            Some(MaxDemand {
+                time: utc(2020, 5, 9, 11, 45, 58),
+                kw: 2.589,
+            })
+        );
+
+        assert_eq!(
+            data.slave_devices[0],
+            Some(SlaveDevice {
+                device_type: 3,
+                equipment_identifier: "7FLO2119033731".into(),
+            })
+        );
+
+        // Dutch meters don't have them
+        let data = parse(DSMR5_SPEC);
+        assert_eq!(data.electricity_data.average_demand, None);
+        assert_eq!(data.electricity_data.max_demand_month, None);
+    }
+
+    #[test]
+    fn slave_devices_without_identifier() {
+        let sample = String::from_utf8(DSMR5_SPEC.to_vec()).unwrap();
+        let sample = sample.replace(
+            "0-1:96.1.0(3232323241424344313233343536373839)",
+            "0-1:96.1.0()",
+        );
+        // The CRC covers everything from the / up to and including the !
+        let end = sample.find('!').unwrap() + 1;
+        let crc = crc16::State::<crc16::ARC>::calculate(&sample.as_bytes()[..end]);
+        let sample = format!("{}{crc:04X}\r\n", &sample[..end]);
+
+        let data = parse(sample.as_bytes());
+        // The readings are still kept, without a device
+        assert_eq!(data.slave_devices[0], None);
+        assert!(data.slave_data[0].is_some());
+    }
+
+    #[test]
+    fn corrupted_telegram() {
+        let mut sample = DSMR5_SPEC.to_vec();
+        let position = sample.windows(5).position(|w| w == b"220.1").unwrap();
+        sample[position] = b'3';
+
+        assert!(matches!(
+            readout(&sample).to_telegram(),
+            Err(dsmr5::Error::InvalidChecksum)
+        ));
+    }
+
+    #[test]
+    fn text_messages() {
+        assert_eq!(parse_text_message("(48656C6C6F)").unwrap(), "Hello");
+        assert_eq!(parse_text_message("()").unwrap(), "");
+
+        for body in ["(48656C6C6)", "(48656C6G6F)", "(4865é6C6F)", "(é)"] {
+            assert!(
+                matches!(parse_text_message(body), Err(Error::InvalidObject(_))),
+                "{body}"
+            );
+        }
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let data = parse(DSMR5_SPEC);
+
+        let json = serde_json::to_string(&data).unwrap();
+        let parsed: TelegramData = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(parsed.electricity_data.time, data.electricity_data.time);
+        assert_eq!(
+            parsed.electricity_data.voltages,
+            data.electricity_data.voltages
+        );
+        assert_eq!(parsed.status, data.status);
+        assert_eq!(parsed.power_failure_events, data.power_failure_events);
+        assert_eq!(parsed.slave_devices, data.slave_devices);
+    }
+
+    #[test]
+    fn database_export() {
+        // Exports of electricity_data_points don't have the currents
+        let data: ElectricityData = serde_json::from_str(
+            r#"{
+                "time": "2025-01-15T11:00:00+00:00",
+                "kwh_import_total_tarif_low": 1.5,
+                "kwh_import_total_tarif_high": 2.5,
+                "kwh_export_total_tarif_low": 0.5,
+                "kwh_export_total_tarif_high": 1.0
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(data.time, utc(2025, 1, 15, 11, 0, 0));
+        assert_eq!(data.current, [0.0; 3]);
+        assert_eq!(data.kwh_balance(), 2.5);
+    }
+}
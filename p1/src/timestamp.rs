@@ -0,0 +1,124 @@
+use chrono::{LocalResult, TimeZone, Utc};
+use chrono_tz::Tz;
+use dsmr5::types::TST;
+
+use crate::Error;
+
+/// Converts a meter timestamp, which is in local time, to UTC.
+///
+/// During the autumn transition the same local hour happens twice.
+/// The DST flag the meter sends along tells us which one is meant.
+pub fn tst_to_utc(timestamp: &TST, timezone: Tz) -> Result<chrono::DateTime<Utc>, Error> {
+    let local_time = timezone.with_ymd_and_hms(
+        timestamp.year as i32 + 2000,
+        timestamp.month as u32,
+        timestamp.day as u32,
+        timestamp.hour as u32,
+        timestamp.minute as u32,
+        timestamp.second as u32,
+    );
+
+    let time = match local_time {
+        LocalResult::Single(time) => time,
+        LocalResult::Ambiguous(summer_time, _) if timestamp.dst => summer_time,
+        LocalResult::Ambiguous(_, winter_time) => winter_time,
+        LocalResult::None => return Err(Error::InvalidTime(format!("{timestamp:?}"))),
+    };
+
+    Ok(time.to_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tst(year: u8, month: u8, day: u8, hour: u8, minute: u8, dst: bool) -> TST {
+        TST {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second: 0,
+            dst,
+        }
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> chrono::DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn regular_winter_and_summer_time() {
+        let tz = chrono_tz::Europe::Amsterdam;
+
+        assert_eq!(
+            tst_to_utc(&tst(25, 1, 15, 12, 0, false), tz).unwrap(),
+            utc(2025, 1, 15, 11, 0)
+        );
+        assert_eq!(
+            tst_to_utc(&tst(25, 7, 15, 12, 0, true), tz).unwrap(),
+            utc(2025, 7, 15, 10, 0)
+        );
+    }
+
+    #[test]
+    fn spring_transition() {
+        let tz = chrono_tz::Europe::Amsterdam;
+
+        // 2025-03-30 02:00 CET jumps to 03:00 CEST
+        assert_eq!(
+            tst_to_utc(&tst(25, 3, 30, 1, 59, false), tz).unwrap(),
+            utc(2025, 3, 30, 0, 59)
+        );
+        assert_eq!(
+            tst_to_utc(&tst(25, 3, 30, 3, 0, true), tz).unwrap(),
+            utc(2025, 3, 30, 1, 0)
+        );
+
+        // This hour doesn't exist
+        assert!(tst_to_utc(&tst(25, 3, 30, 2, 30, false), tz).is_err());
+    }
+
+    #[test]
+    fn autumn_transition() {
+        let tz = chrono_tz::Europe::Amsterdam;
+
+        // 2025-10-26 03:00 CEST falls back to 02:00 CET, so 02:xx happens twice
+        assert_eq!(
+            tst_to_utc(&tst(25, 10, 26, 1, 59, true), tz).unwrap(),
+            utc(2025, 10, 25, 23, 59)
+        );
+        assert_eq!(
+            tst_to_utc(&tst(25, 10, 26, 2, 30, true), tz).unwrap(),
+            utc(2025, 10, 26, 0, 30)
+        );
+        assert_eq!(
+            tst_to_utc(&tst(25, 10, 26, 2, 30, false), tz).unwrap(),
+            utc(2025, 10, 26, 1, 30)
+        );
+        assert_eq!(
+            tst_to_utc(&tst(25, 10, 26, 3, 0, false), tz).unwrap(),
+            utc(2025, 10, 26, 2, 0)
+        );
+    }
+
+    #[test]
+    fn other_timezone() {
+        let tz = chrono_tz::Europe::London;
+
+        assert_eq!(
+            tst_to_utc(&tst(25, 1, 15, 12, 0, false), tz).unwrap(),
+            utc(2025, 1, 15, 12, 0)
+        );
+        assert_eq!(
+            tst_to_utc(&tst(25, 10, 26, 1, 30, true), tz).unwrap(),
+            utc(2025, 10, 26, 0, 30)
+        );
+        assert_eq!(
+            tst_to_utc(&tst(25, 10, 26, 1, 30, false), tz).unwrap(),
+            utc(2025, 10, 26, 1, 30)
+        );
+    }
+}
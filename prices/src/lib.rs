@@ -0,0 +1,304 @@
+//! Energy prices: day-ahead prices from ENTSO-E XML or CSV files, and the tariffs of contracts.
+//!
+//! All prices are in € per kWh.
+
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Could not read file: {0}")]
+    Read(#[from] io::Error),
+    #[error("Invalid XML: {0}")]
+    Xml(#[from] roxmltree::Error),
+    #[error("Invalid price file: {0}")]
+    Invalid(String),
+}
+
+/// The day-ahead price of one interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Price {
+    /// When the interval starts
+    pub time: DateTime<Utc>,
+    /// An hour or a quarter-hour
+    pub duration: TimeDelta,
+    /// € per kWh
+    pub price: f32,
+}
+
+impl Price {
+    pub fn end(&self) -> DateTime<Utc> {
+        self.time + self.duration
+    }
+}
+
+/// Reads an ENTSO-E XML (`.xml`) or CSV (`.csv`) file
+pub fn read_file(path: &Path) -> Result<Vec<Price>, Error> {
+    let contents = fs::read_to_string(path)?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("xml") => parse_entsoe(&contents),
+        Some("csv") => parse_csv(&contents),
+        _ => Err(Error::Invalid("expected an .xml or .csv file".into())),
+    }
+}
+
+/// Parses an ENTSO-E day-ahead prices document (`Publication_MarketDocument`, A44), with prices in € per MWh.
+///
+/// Points that are left out because they have the same price as the point before (curve type A03) are filled
+/// in.
+pub fn parse_entsoe(xml: &str) -> Result<Vec<Price>, Error> {
+    let document = roxmltree::Document::parse(xml)?;
+    let root = document.root_element();
+    if root.tag_name().name() != "Publication_MarketDocument" {
+        return Err(Error::Invalid(format!(
+            "expected a Publication_MarketDocument, got {}",
+            root.tag_name().name()
+        )));
+    }
+
+    let mut prices = Vec::new();
+    for series in children(root, "TimeSeries") {
+        let unit = child_text(series, "price_Measure_Unit.name")?;
+        if unit != "MWH" {
+            return Err(Error::Invalid(format!("unsupported price unit {unit}")));
+        }
+
+        for period in children(series, "Period") {
+            let interval = child(period, "timeInterval")?;
+            let start = parse_time(child_text(interval, "start")?)?;
+            let end = parse_time(child_text(interval, "end")?)?;
+            let duration = parse_resolution(child_text(period, "resolution")?)?;
+
+            let mut points = BTreeMap::new();
+            for point in children(period, "Point") {
+                let position: i32 = parse_number(child_text(point, "position")?)?;
+                let price: f32 = parse_number(child_text(point, "price.amount")?)?;
+                points.insert(position, price);
+            }
+
+            let mut price = None;
+            let mut time = start;
+            let mut position = 1;
+            while time < end {
+                if let Some(point) = points.get(&position) {
+                    price = Some(*point);
+                }
+                if let Some(price) = price {
+                    prices.push(Price {
+                        time,
+                        duration,
+                        price: price / 1000.0,
+                    });
+                }
+                time += duration;
+                position += 1;
+            }
+        }
+    }
+
+    if prices.is_empty() {
+        return Err(Error::Invalid("no prices in the document".into()));
+    }
+    prices.sort_by_key(|price| price.time);
+    Ok(prices)
+}
+
+/// Parses CSV with a `start,end,price` line per interval, times in RFC 3339 and prices in € per MWh like
+/// ENTSO-E's. A header line is skipped.
+pub fn parse_csv(csv: &str) -> Result<Vec<Price>, Error> {
+    let mut prices = Vec::new();
+    for (i, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (i == 0 && line.starts_with(|c: char| c.is_alphabetic())) {
+            continue;
+        }
+
+        let invalid = |message: &str| Error::Invalid(format!("line {}: {message}", i + 1));
+        let [start, end, price] = line
+            .split(',')
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| invalid("expected start,end,price"))?;
+        let time = DateTime::parse_from_rfc3339(start)
+            .map_err(|e| invalid(&format!("invalid start: {e}")))?
+            .to_utc();
+        let end = DateTime::parse_from_rfc3339(end)
+            .map_err(|e| invalid(&format!("invalid end: {e}")))?
+            .to_utc();
+        let price: f32 = price
+            .parse()
+            .map_err(|e| invalid(&format!("invalid price: {e}")))?;
+        if end <= time {
+            return Err(invalid("end must be after start"));
+        }
+
+        prices.push(Price {
+            time,
+            duration: end - time,
+            price: price / 1000.0,
+        });
+    }
+
+    if prices.is_empty() {
+        return Err(Error::Invalid("no prices in the file".into()));
+    }
+    prices.sort_by_key(|price| price.time);
+    Ok(prices)
+}
+
+/// € per kWh at `time`, `prices` sorted by time. Where prices overlap, like an hourly price and the
+/// quarter-hourly prices that replaced it, the one that started last applies.
+pub fn price_at(prices: &[Price], time: DateTime<Utc>) -> Option<f32> {
+    let next = prices.partition_point(|price| price.time <= time);
+    prices[..next]
+        .last()
+        .filter(|price| time < price.end())
+        .map(|price| price.price)
+}
+
+/// What a contract charges for imported energy and pays for exported energy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tariff {
+    /// Fixed prices per tariff period of the meter
+    Fixed {
+        import_high: f32,
+        import_low: f32,
+        export_high: f32,
+        export_low: f32,
+    },
+    /// Follows the day-ahead price, e.g. `import_factor` 1.21 for VAT and `import_markup` for the supplier's
+    /// margin
+    Dynamic {
+        import_factor: f32,
+        import_markup: f32,
+        export_factor: f32,
+        export_markup: f32,
+    },
+}
+
+impl Tariff {
+    /// € paid per imported kWh, during the meter's `high` tariff period or not and with `spot` the day-ahead
+    /// price. `None` for a dynamic tariff without a day-ahead price.
+    pub fn import_price(&self, high: bool, spot: Option<f32>) -> Option<f32> {
+        match *self {
+            Tariff::Fixed {
+                import_high,
+                import_low,
+                ..
+            } => Some(if high { import_high } else { import_low }),
+            Tariff::Dynamic {
+                import_factor,
+                import_markup,
+                ..
+            } => spot.map(|spot| spot * import_factor + import_markup),
+        }
+    }
+
+    /// € received per exported kWh, like [`Tariff::import_price`]
+    pub fn export_price(&self, high: bool, spot: Option<f32>) -> Option<f32> {
+        match *self {
+            Tariff::Fixed {
+                export_high,
+                export_low,
+                ..
+            } => Some(if high { export_high } else { export_low }),
+            Tariff::Dynamic {
+                export_factor,
+                export_markup,
+                ..
+            } => spot.map(|spot| spot * export_factor + export_markup),
+        }
+    }
+}
+
+fn children<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> {
+    node.children()
+        .filter(move |child| child.tag_name().name() == name)
+}
+
+fn child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &'static str,
+) -> Result<roxmltree::Node<'a, 'input>, Error> {
+    children(node, name)
+        .next()
+        .ok_or_else(|| Error::Invalid(format!("missing {name} in {}", node.tag_name().name())))
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &'static str) -> Result<&'a str, Error> {
+    Ok(child(node, name)?.text().unwrap_or_default().trim())
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| Error::Invalid(format!("invalid number {value:?}: {e}")))
+}
+
+/// ENTSO-E times leave out the seconds, e.g. `2026-10-17T22:00Z`
+fn parse_time(value: &str) -> Result<DateTime<Utc>, Error> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%MZ")
+        .map(|time| time.and_utc())
+        .or_else(|_| DateTime::parse_from_rfc3339(value).map(|time| time.to_utc()))
+        .map_err(|e| Error::Invalid(format!("invalid time {value:?}: {e}")))
+}
+
+/// An ISO 8601 duration in minutes or hours, e.g. `PT15M` or `PT60M`
+fn parse_resolution(value: &str) -> Result<TimeDelta, Error> {
+    let invalid = || Error::Invalid(format!("unsupported resolution {value:?}"));
+    let amount = value.strip_prefix("PT").ok_or_else(invalid)?;
+    let duration = if let Some(minutes) = amount.strip_suffix('M') {
+        TimeDelta::minutes(minutes.parse().map_err(|_| invalid())?)
+    } else if let Some(hours) = amount.strip_suffix('H') {
+        TimeDelta::hours(hours.parse().map_err(|_| invalid())?)
+    } else {
+        return Err(invalid());
+    };
+    if duration <= TimeDelta::zero() {
+        return Err(invalid());
+    }
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entsoe_fills_left_out_points() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Publication_MarketDocument xmlns="urn:iec62325.351:tc57wg16:451-3:publicationdocument:7:3">
+    <TimeSeries>
+        <currency_Unit.name>EUR</currency_Unit.name>
+        <price_Measure_Unit.name>MWH</price_Measure_Unit.name>
+        <curveType>A03</curveType>
+        <Period>
+            <timeInterval>
+                <start>2026-10-17T22:00Z</start>
+                <end>2026-10-17T23:00Z</end>
+            </timeInterval>
+            <resolution>PT15M</resolution>
+            <Point><position>1</position><price.amount>100.5</price.amount></Point>
+            <Point><position>3</position><price.amount>-20</price.amount></Point>
+        </Period>
+    </TimeSeries>
+</Publication_MarketDocument>"#;
+
+        let prices = parse_entsoe(xml).unwrap();
+        let amounts: Vec<f32> = prices.iter().map(|price| price.price).collect();
+        assert_eq!(amounts, [0.1005, 0.1005, -0.02, -0.02]);
+        assert_eq!(prices[3].duration, TimeDelta::minutes(15));
+
+        let time = prices[1].time + TimeDelta::minutes(5);
+        assert_eq!(price_at(&prices, time), Some(0.1005));
+        assert_eq!(price_at(&prices, prices[3].end()), None);
+    }
+}
@@ -0,0 +1,21 @@
+-- Add up migration script here
+
+CREATE TABLE IF NOT EXISTS meter_status_points (
+	time TIMESTAMPTZ PRIMARY KEY,
+
+	equipment_identifier TEXT NOT NULL,
+	tariff_indicator SMALLINT NOT NULL,
+
+	power_failures INTEGER NOT NULL,
+	long_power_failures INTEGER NOT NULL,
+
+	voltage_sags INTEGER[3] NOT NULL,
+	voltage_swells INTEGER[3] NOT NULL,
+
+	text_message TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS power_failure_events (
+	end_time TIMESTAMPTZ PRIMARY KEY,
+	duration_seconds INTEGER NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+
+ALTER TABLE electricity_data_points
+DROP COLUMN IF EXISTS received_time;
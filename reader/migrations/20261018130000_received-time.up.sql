@@ -0,0 +1,4 @@
+-- Add up migration script here
+
+ALTER TABLE electricity_data_points
+ADD COLUMN received_time TIMESTAMPTZ;
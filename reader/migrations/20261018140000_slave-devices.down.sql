@@ -0,0 +1,6 @@
+-- Add down migration script here
+
+ALTER TABLE slave_data_points
+DROP COLUMN IF EXISTS equipment_identifier;
+
+DROP TABLE IF EXISTS slave_devices;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+
+CREATE TABLE IF NOT EXISTS slave_devices (
+	equipment_identifier TEXT PRIMARY KEY,
+
+	-- The channel the device was last seen on (0-3)
+	channel SMALLINT NOT NULL,
+	-- M-Bus device type, e.g. 3 for gas
+	device_type SMALLINT NOT NULL,
+
+	name TEXT NOT NULL,
+	unit TEXT NOT NULL,
+
+	updated TIMESTAMPTZ NOT NULL
+);
+
+ALTER TABLE slave_data_points
+ADD COLUMN equipment_identifier TEXT;
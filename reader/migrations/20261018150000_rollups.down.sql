@@ -0,0 +1,5 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS electricity_rollup_day;
+DROP TABLE IF EXISTS electricity_rollup_hour;
+DROP TABLE IF EXISTS electricity_rollup_minute;
@@ -0,0 +1,61 @@
+-- Add up migration script here
+
+CREATE TABLE IF NOT EXISTS electricity_rollup_minute (
+	time TIMESTAMPTZ PRIMARY KEY,
+	-- Number of telegrams in the bucket
+	samples INTEGER NOT NULL,
+
+	-- Active power per phase in kW, import minus export
+	power_min REAL[3] NOT NULL,
+	power_max REAL[3] NOT NULL,
+	power_avg REAL[3] NOT NULL,
+
+	kwh_import_total_tarif_low_first REAL NOT NULL,
+	kwh_import_total_tarif_low_last REAL NOT NULL,
+	kwh_import_total_tarif_high_first REAL NOT NULL,
+	kwh_import_total_tarif_high_last REAL NOT NULL,
+	kwh_export_total_tarif_low_first REAL NOT NULL,
+	kwh_export_total_tarif_low_last REAL NOT NULL,
+	kwh_export_total_tarif_high_first REAL NOT NULL,
+	kwh_export_total_tarif_high_last REAL NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS electricity_rollup_hour (
+	time TIMESTAMPTZ PRIMARY KEY,
+	-- Number of telegrams in the bucket
+	samples INTEGER NOT NULL,
+
+	-- Active power per phase in kW, import minus export
+	power_min REAL[3] NOT NULL,
+	power_max REAL[3] NOT NULL,
+	power_avg REAL[3] NOT NULL,
+
+	kwh_import_total_tarif_low_first REAL NOT NULL,
+	kwh_import_total_tarif_low_last REAL NOT NULL,
+	kwh_import_total_tarif_high_first REAL NOT NULL,
+	kwh_import_total_tarif_high_last REAL NOT NULL,
+	kwh_export_total_tarif_low_first REAL NOT NULL,
+	kwh_export_total_tarif_low_last REAL NOT NULL,
+	kwh_export_total_tarif_high_first REAL NOT NULL,
+	kwh_export_total_tarif_high_last REAL NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS electricity_rollup_day (
+	time TIMESTAMPTZ PRIMARY KEY,
+	-- Number of telegrams in the bucket
+	samples INTEGER NOT NULL,
+
+	-- Active power per phase in kW, import minus export
+	power_min REAL[3] NOT NULL,
+	power_max REAL[3] NOT NULL,
+	power_avg REAL[3] NOT NULL,
+
+	kwh_import_total_tarif_low_first REAL NOT NULL,
+	kwh_import_total_tarif_low_last REAL NOT NULL,
+	kwh_import_total_tarif_high_first REAL NOT NULL,
+	kwh_import_total_tarif_high_last REAL NOT NULL,
+	kwh_export_total_tarif_low_first REAL NOT NULL,
+	kwh_export_total_tarif_low_last REAL NOT NULL,
+	kwh_export_total_tarif_high_first REAL NOT NULL,
+	kwh_export_total_tarif_high_last REAL NOT NULL
+);
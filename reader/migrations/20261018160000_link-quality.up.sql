@@ -0,0 +1,17 @@
+-- Add up migration script here
+
+-- How healthy the P1 link was, per hour of our own clock.
+-- A meter sends 3600 telegrams an hour, so fewer telegrams without errors means the link was down.
+CREATE TABLE IF NOT EXISTS p1_link_quality (
+	time TIMESTAMPTZ PRIMARY KEY,
+	-- Telegrams that were parsed
+	telegrams INTEGER NOT NULL DEFAULT 0,
+	-- Telegrams dropped because the CRC didn't match
+	crc_errors INTEGER NOT NULL DEFAULT 0,
+	-- Telegrams dropped because they were cut off, too long or not split into objects
+	framing_errors INTEGER NOT NULL DEFAULT 0,
+	-- Telegrams dropped because their values made no sense, e.g. a time that doesn't exist
+	data_errors INTEGER NOT NULL DEFAULT 0,
+	-- Objects that couldn't be parsed in telegrams that were otherwise fine
+	object_errors INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,45 @@
+-- Add down migration script here
+
+-- Only the default meter's data fits in the old tables
+
+DELETE FROM electricity_data_points WHERE meter_id <> 'main';
+ALTER TABLE electricity_data_points DROP CONSTRAINT IF EXISTS electricity_data_points_pkey;
+ALTER TABLE electricity_data_points DROP COLUMN IF EXISTS meter_id;
+ALTER TABLE electricity_data_points ADD PRIMARY KEY (time);
+
+DELETE FROM slave_data_points WHERE meter_id <> 'main';
+ALTER TABLE slave_data_points DROP CONSTRAINT IF EXISTS slave_data_points_pkey;
+ALTER TABLE slave_data_points DROP COLUMN IF EXISTS meter_id;
+ALTER TABLE slave_data_points ADD PRIMARY KEY (time, id);
+
+DELETE FROM meter_status_points WHERE meter_id <> 'main';
+ALTER TABLE meter_status_points DROP CONSTRAINT IF EXISTS meter_status_points_pkey;
+ALTER TABLE meter_status_points DROP COLUMN IF EXISTS meter_id;
+ALTER TABLE meter_status_points ADD PRIMARY KEY (time);
+
+DELETE FROM power_failure_events WHERE meter_id <> 'main';
+ALTER TABLE power_failure_events DROP CONSTRAINT IF EXISTS power_failure_events_pkey;
+ALTER TABLE power_failure_events DROP COLUMN IF EXISTS meter_id;
+ALTER TABLE power_failure_events ADD PRIMARY KEY (end_time);
+
+DELETE FROM electricity_rollup_minute WHERE meter_id <> 'main';
+ALTER TABLE electricity_rollup_minute DROP CONSTRAINT IF EXISTS electricity_rollup_minute_pkey;
+ALTER TABLE electricity_rollup_minute DROP COLUMN IF EXISTS meter_id;
+ALTER TABLE electricity_rollup_minute ADD PRIMARY KEY (time);
+
+DELETE FROM electricity_rollup_hour WHERE meter_id <> 'main';
+ALTER TABLE electricity_rollup_hour DROP CONSTRAINT IF EXISTS electricity_rollup_hour_pkey;
+ALTER TABLE electricity_rollup_hour DROP COLUMN IF EXISTS meter_id;
+ALTER TABLE electricity_rollup_hour ADD PRIMARY KEY (time);
+
+DELETE FROM electricity_rollup_day WHERE meter_id <> 'main';
+ALTER TABLE electricity_rollup_day DROP CONSTRAINT IF EXISTS electricity_rollup_day_pkey;
+ALTER TABLE electricity_rollup_day DROP COLUMN IF EXISTS meter_id;
+ALTER TABLE electricity_rollup_day ADD PRIMARY KEY (time);
+
+DELETE FROM p1_link_quality WHERE meter_id <> 'main';
+ALTER TABLE p1_link_quality DROP CONSTRAINT IF EXISTS p1_link_quality_pkey;
+ALTER TABLE p1_link_quality DROP COLUMN IF EXISTS meter_id;
+ALTER TABLE p1_link_quality ADD PRIMARY KEY (time);
+
+ALTER TABLE slave_devices DROP COLUMN IF EXISTS meter_id;
@@ -0,0 +1,48 @@
+-- Add up migration script here
+
+-- Every row belongs to one of the meters in the reader's config.
+-- Everything from before there could be more than one meter is the default meter's.
+
+ALTER TABLE electricity_data_points ADD COLUMN IF NOT EXISTS meter_id TEXT NOT NULL DEFAULT 'main';
+ALTER TABLE electricity_data_points ALTER COLUMN meter_id DROP DEFAULT;
+ALTER TABLE electricity_data_points DROP CONSTRAINT IF EXISTS electricity_data_points_pkey;
+ALTER TABLE electricity_data_points ADD PRIMARY KEY (meter_id, time);
+
+ALTER TABLE slave_data_points ADD COLUMN IF NOT EXISTS meter_id TEXT NOT NULL DEFAULT 'main';
+ALTER TABLE slave_data_points ALTER COLUMN meter_id DROP DEFAULT;
+ALTER TABLE slave_data_points DROP CONSTRAINT IF EXISTS slave_data_points_pkey;
+ALTER TABLE slave_data_points ADD PRIMARY KEY (meter_id, time, id);
+
+ALTER TABLE meter_status_points ADD COLUMN IF NOT EXISTS meter_id TEXT NOT NULL DEFAULT 'main';
+ALTER TABLE meter_status_points ALTER COLUMN meter_id DROP DEFAULT;
+ALTER TABLE meter_status_points DROP CONSTRAINT IF EXISTS meter_status_points_pkey;
+ALTER TABLE meter_status_points ADD PRIMARY KEY (meter_id, time);
+
+ALTER TABLE power_failure_events ADD COLUMN IF NOT EXISTS meter_id TEXT NOT NULL DEFAULT 'main';
+ALTER TABLE power_failure_events ALTER COLUMN meter_id DROP DEFAULT;
+ALTER TABLE power_failure_events DROP CONSTRAINT IF EXISTS power_failure_events_pkey;
+ALTER TABLE power_failure_events ADD PRIMARY KEY (meter_id, end_time);
+
+ALTER TABLE electricity_rollup_minute ADD COLUMN IF NOT EXISTS meter_id TEXT NOT NULL DEFAULT 'main';
+ALTER TABLE electricity_rollup_minute ALTER COLUMN meter_id DROP DEFAULT;
+ALTER TABLE electricity_rollup_minute DROP CONSTRAINT IF EXISTS electricity_rollup_minute_pkey;
+ALTER TABLE electricity_rollup_minute ADD PRIMARY KEY (meter_id, time);
+
+ALTER TABLE electricity_rollup_hour ADD COLUMN IF NOT EXISTS meter_id TEXT NOT NULL DEFAULT 'main';
+ALTER TABLE electricity_rollup_hour ALTER COLUMN meter_id DROP DEFAULT;
+ALTER TABLE electricity_rollup_hour DROP CONSTRAINT IF EXISTS electricity_rollup_hour_pkey;
+ALTER TABLE electricity_rollup_hour ADD PRIMARY KEY (meter_id, time);
+
+ALTER TABLE electricity_rollup_day ADD COLUMN IF NOT EXISTS meter_id TEXT NOT NULL DEFAULT 'main';
+ALTER TABLE electricity_rollup_day ALTER COLUMN meter_id DROP DEFAULT;
+ALTER TABLE electricity_rollup_day DROP CONSTRAINT IF EXISTS electricity_rollup_day_pkey;
+ALTER TABLE electricity_rollup_day ADD PRIMARY KEY (meter_id, time);
+
+ALTER TABLE p1_link_quality ADD COLUMN IF NOT EXISTS meter_id TEXT NOT NULL DEFAULT 'main';
+ALTER TABLE p1_link_quality ALTER COLUMN meter_id DROP DEFAULT;
+ALTER TABLE p1_link_quality DROP CONSTRAINT IF EXISTS p1_link_quality_pkey;
+ALTER TABLE p1_link_quality ADD PRIMARY KEY (meter_id, time);
+
+-- The meter the device was last seen on
+ALTER TABLE slave_devices ADD COLUMN IF NOT EXISTS meter_id TEXT NOT NULL DEFAULT 'main';
+ALTER TABLE slave_devices ALTER COLUMN meter_id DROP DEFAULT;
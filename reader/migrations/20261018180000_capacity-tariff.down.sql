@@ -0,0 +1,7 @@
+-- Add down migration script here
+
+DROP TABLE IF EXISTS capacity_peaks;
+
+ALTER TABLE electricity_data_points DROP COLUMN IF EXISTS max_demand_month_time;
+ALTER TABLE electricity_data_points DROP COLUMN IF EXISTS max_demand_month;
+ALTER TABLE electricity_data_points DROP COLUMN IF EXISTS average_demand;
@@ -0,0 +1,23 @@
+-- Add up migration script here
+
+-- The capacity tariff objects of Belgian e-MUCS meters, NULL for other meters
+-- kW, the average import power of the current quarter-hour so far (1-0:1.4.0)
+ALTER TABLE electricity_data_points ADD COLUMN IF NOT EXISTS average_demand REAL;
+-- kW and when, the highest quarter-hour average import power of the month so far (1-0:1.6.0)
+ALTER TABLE electricity_data_points ADD COLUMN IF NOT EXISTS max_demand_month REAL;
+ALTER TABLE electricity_data_points ADD COLUMN IF NOT EXISTS max_demand_month_time TIMESTAMPTZ;
+
+-- The highest quarter-hour average import power per month, which the Flemish capacity tariff bills
+CREATE TABLE IF NOT EXISTS capacity_peaks (
+	meter_id TEXT NOT NULL,
+	-- The first day of the month, in the meter's timezone
+	month DATE NOT NULL,
+	-- When the meter registered the peak, or when the quarter-hour ended
+	time TIMESTAMPTZ NOT NULL,
+	-- kW
+	power REAL NOT NULL,
+	-- 'meter' when the meter reported it, 'counters' when it was worked out from the import counters
+	source TEXT NOT NULL,
+
+	PRIMARY KEY (meter_id, month)
+);
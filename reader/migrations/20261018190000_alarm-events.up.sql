@@ -0,0 +1,22 @@
+-- Add up migration script here
+
+-- Alarms raised by the rules in [[reader.alarms]], one row per alarm from when it was raised until it cleared
+CREATE TABLE IF NOT EXISTS alarm_events (
+	meter_id TEXT NOT NULL,
+	-- The name of the rule
+	rule TEXT NOT NULL,
+	-- 'L1', 'L2' or 'L3'
+	phase TEXT NOT NULL,
+	-- When the alarm was raised, by the meter's clock
+	time TIMESTAMPTZ NOT NULL,
+	-- NULL while the alarm is active
+	cleared_time TIMESTAMPTZ,
+	-- 'current', 'voltage', 'power_import' or 'power_export'
+	quantity TEXT NOT NULL,
+	-- The threshold that was crossed, in A, V or kW
+	threshold REAL NOT NULL,
+	-- The value when the alarm was raised, the worst value once it cleared
+	value REAL NOT NULL,
+
+	PRIMARY KEY (meter_id, rule, phase, time)
+);
@@ -0,0 +1,25 @@
+-- Add up migration script here
+
+-- Per-phase statistics per day, from every telegram
+CREATE TABLE IF NOT EXISTS phase_stats_daily (
+	meter_id TEXT NOT NULL,
+	-- In the meter's timezone
+	day DATE NOT NULL,
+	-- Telegrams the statistics are over
+	samples INTEGER NOT NULL,
+	-- A per phase
+	current_avg REAL[] NOT NULL,
+	current_max REAL[] NOT NULL,
+	-- kW per phase, import minus export
+	net_power_avg REAL[] NOT NULL,
+	-- A, estimated from the phase currents assuming they're 120 degrees apart and in phase with the voltage
+	neutral_current_avg REAL NOT NULL,
+	neutral_current_max REAL NOT NULL,
+	-- Telegrams of a three phase meter with enough current to tell the imbalance
+	imbalance_samples INTEGER NOT NULL,
+	-- %, the largest deviation of a phase current from the average of the three. NULL without imbalance samples.
+	imbalance_avg REAL,
+	imbalance_max REAL,
+
+	PRIMARY KEY (meter_id, day)
+);
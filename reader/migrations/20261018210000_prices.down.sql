@@ -0,0 +1,10 @@
+-- Add down migration script here
+
+ALTER TABLE electricity_rollup_day DROP COLUMN IF EXISTS export_revenue;
+ALTER TABLE electricity_rollup_day DROP COLUMN IF EXISTS import_cost;
+ALTER TABLE electricity_rollup_hour DROP COLUMN IF EXISTS export_revenue;
+ALTER TABLE electricity_rollup_hour DROP COLUMN IF EXISTS import_cost;
+ALTER TABLE electricity_rollup_minute DROP COLUMN IF EXISTS export_revenue;
+ALTER TABLE electricity_rollup_minute DROP COLUMN IF EXISTS import_cost;
+
+DROP TABLE IF EXISTS prices;
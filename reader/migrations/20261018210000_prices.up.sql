@@ -0,0 +1,21 @@
+-- Add up migration script here
+
+-- Day-ahead prices, imported from ENTSO-E XML or CSV files
+CREATE TABLE IF NOT EXISTS prices (
+	-- When the interval starts
+	time TIMESTAMPTZ PRIMARY KEY,
+	-- Seconds, 3600 for hourly and 900 for quarter-hourly prices
+	duration INTEGER NOT NULL,
+	-- € per kWh, without the markup of the contract
+	price REAL NOT NULL,
+	-- The file the price was imported from
+	source TEXT NOT NULL
+);
+
+-- €, what the imported energy cost and the exported energy paid, NULL without a tariff or a price
+ALTER TABLE electricity_rollup_minute ADD COLUMN IF NOT EXISTS import_cost REAL;
+ALTER TABLE electricity_rollup_minute ADD COLUMN IF NOT EXISTS export_revenue REAL;
+ALTER TABLE electricity_rollup_hour ADD COLUMN IF NOT EXISTS import_cost REAL;
+ALTER TABLE electricity_rollup_hour ADD COLUMN IF NOT EXISTS export_revenue REAL;
+ALTER TABLE electricity_rollup_day ADD COLUMN IF NOT EXISTS import_cost REAL;
+ALTER TABLE electricity_rollup_day ADD COLUMN IF NOT EXISTS export_revenue REAL;
@@ -0,0 +1,22 @@
+-- Add up migration script here
+
+-- The always-on consumption of every night
+CREATE TABLE IF NOT EXISTS baseload_daily (
+	meter_id TEXT NOT NULL,
+	-- The local day the night ends on
+	day DATE NOT NULL,
+	-- Minutes of the night with rolled up data
+	minutes INTEGER NOT NULL,
+	-- kW, the lowest minute average of net power plus PV output, NULL when too much of the night is missing
+	baseload REAL,
+	-- When that minute started
+	time TIMESTAMPTZ,
+	-- Whether PV output from the solar-reader's database was added
+	solar_corrected BOOLEAN NOT NULL,
+	-- kW, the median baseload of the days before, NULL without enough of them
+	reference REAL,
+	-- The baseload is more than the configured minimum above the reference
+	increased BOOLEAN NOT NULL,
+
+	PRIMARY KEY (meter_id, day)
+);
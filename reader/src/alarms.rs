@@ -0,0 +1,490 @@
+use std::{collections::VecDeque, time::Duration};
+
+use chrono::{DateTime, Utc};
+use mqtt_publisher::MqttPublisher;
+use notifier::{Notification, Notifier, Priority};
+use p1::ElectricityData;
+use serde::Serialize;
+use settings::{AlarmQuantity, AlarmRule, Phase};
+use sqlx::postgres::PgPool;
+use tokio::sync::mpsc;
+
+/// How long to wait before writing events again after the database failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An alarm that was raised or cleared, which is sent to every sink
+#[derive(Debug, Clone, Serialize)]
+pub struct AlarmEvent {
+    pub meter_id: String,
+    /// The name of the rule
+    pub rule: String,
+    pub quantity: &'static str,
+    pub phase: &'static str,
+    pub state: AlarmState,
+    /// When the alarm was raised, by the meter's clock
+    pub raised_time: DateTime<Utc>,
+    /// When the alarm was raised or cleared, by the meter's clock
+    pub time: DateTime<Utc>,
+    pub threshold: f32,
+    /// The value when the alarm was raised, and the worst value while it was active when it cleared
+    pub value: f32,
+    pub unit: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmState {
+    Raised,
+    Cleared,
+}
+
+/// Checks the alarm rules of one meter against its telegrams
+pub struct MeterAlarms {
+    meter_id: String,
+    alarms: Vec<Alarm>,
+}
+
+/// A rule on one phase
+struct Alarm {
+    rule: AlarmRule,
+    phase: Phase,
+    state: State,
+}
+
+enum State {
+    Normal,
+    /// Outside the threshold since then, but not for long enough yet
+    Pending {
+        since: DateTime<Utc>,
+    },
+    Active {
+        raised_time: DateTime<Utc>,
+        threshold: Threshold,
+        worst: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Threshold {
+    Above(f32),
+    Below(f32),
+}
+
+impl Threshold {
+    /// The threshold of `rule` that `value` is outside of
+    fn crossed(rule: &AlarmRule, value: f32) -> Option<Self> {
+        match (rule.above, rule.below) {
+            (Some(above), _) if value > above => Some(Self::Above(above)),
+            (_, Some(below)) if value < below => Some(Self::Below(below)),
+            _ => None,
+        }
+    }
+
+    fn cleared(self, value: f32, hysteresis: f32) -> bool {
+        match self {
+            Self::Above(above) => value <= above - hysteresis,
+            Self::Below(below) => value >= below + hysteresis,
+        }
+    }
+
+    fn worst(self, a: f32, b: f32) -> f32 {
+        match self {
+            Self::Above(_) => a.max(b),
+            Self::Below(_) => a.min(b),
+        }
+    }
+
+    fn value(self) -> f32 {
+        match self {
+            Self::Above(value) | Self::Below(value) => value,
+        }
+    }
+}
+
+impl MeterAlarms {
+    /// Only keeps the rules that apply to the meter
+    pub fn new(meter_id: &str, rules: &[AlarmRule]) -> Self {
+        let alarms = rules
+            .iter()
+            .filter(|rule| rule.meter.as_deref().is_none_or(|meter| meter == meter_id))
+            .flat_map(|rule| {
+                let phases = match rule.phase {
+                    Some(phase) => vec![phase],
+                    None => Phase::ALL.to_vec(),
+                };
+                phases.into_iter().map(|phase| Alarm {
+                    rule: rule.clone(),
+                    phase,
+                    state: State::Normal,
+                })
+            })
+            .collect();
+
+        Self {
+            meter_id: meter_id.into(),
+            alarms,
+        }
+    }
+
+    /// Returns the alarms that were raised or cleared by this telegram.
+    ///
+    /// An alarm is only raised once while it's active, and only cleared once the value is back inside the
+    /// threshold by the rule's hysteresis, so a value hovering around the threshold doesn't flood the sinks.
+    pub fn update(&mut self, data: &ElectricityData) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+
+        for alarm in &mut self.alarms {
+            let phase = alarm.phase.index();
+            let value = match alarm.rule.quantity {
+                AlarmQuantity::Current => data.current[phase],
+                // Meters without voltages, or without this phase, send nothing, which reads as 0
+                AlarmQuantity::Voltage if data.voltages[phase] == 0.0 => continue,
+                AlarmQuantity::Voltage => data.voltages[phase],
+                AlarmQuantity::PowerImport => data.active_powers_import[phase],
+                AlarmQuantity::PowerExport => data.active_powers_export[phase],
+            };
+
+            let event = |state, raised_time, threshold: Threshold, value| AlarmEvent {
+                meter_id: self.meter_id.clone(),
+                rule: alarm.rule.name.clone(),
+                quantity: quantity_name(alarm.rule.quantity),
+                phase: phase_name(alarm.phase),
+                state,
+                raised_time,
+                time: data.time,
+                threshold: threshold.value(),
+                value,
+                unit: unit(alarm.rule.quantity),
+            };
+
+            alarm.state = match alarm.state {
+                State::Normal | State::Pending { .. } => {
+                    match Threshold::crossed(&alarm.rule, value) {
+                        None => State::Normal,
+                        Some(threshold) => {
+                            let since = match alarm.state {
+                                State::Pending { since } => since,
+                                _ => data.time,
+                            };
+                            if (data.time - since).to_std().unwrap_or_default()
+                                >= alarm.rule.duration
+                            {
+                                events.push(event(AlarmState::Raised, data.time, threshold, value));
+                                State::Active {
+                                    raised_time: data.time,
+                                    threshold,
+                                    worst: value,
+                                }
+                            } else {
+                                State::Pending { since }
+                            }
+                        }
+                    }
+                }
+                State::Active {
+                    raised_time,
+                    threshold,
+                    worst,
+                } => {
+                    let worst = threshold.worst(worst, value);
+                    if threshold.cleared(value, alarm.rule.hysteresis) {
+                        events.push(event(AlarmState::Cleared, raised_time, threshold, worst));
+                        State::Normal
+                    } else {
+                        State::Active {
+                            raised_time,
+                            threshold,
+                            worst,
+                        }
+                    }
+                }
+            };
+        }
+
+        events
+    }
+}
+
+fn quantity_name(quantity: AlarmQuantity) -> &'static str {
+    match quantity {
+        AlarmQuantity::Current => "current",
+        AlarmQuantity::Voltage => "voltage",
+        AlarmQuantity::PowerImport => "power_import",
+        AlarmQuantity::PowerExport => "power_export",
+    }
+}
+
+fn unit(quantity: AlarmQuantity) -> &'static str {
+    match quantity {
+        AlarmQuantity::Current => "A",
+        AlarmQuantity::Voltage => "V",
+        AlarmQuantity::PowerImport | AlarmQuantity::PowerExport => "kW",
+    }
+}
+
+fn phase_name(phase: Phase) -> &'static str {
+    match phase {
+        Phase::L1 => "L1",
+        Phase::L2 => "L2",
+        Phase::L3 => "L3",
+    }
+}
+
+/// Somewhere alarm events go. Sending must not block, the main loop calls it.
+pub trait AlarmSink {
+    fn send(&mut self, event: &AlarmEvent);
+}
+
+/// Logs raised alarms as warnings and cleared ones as info
+pub struct LogSink;
+
+impl AlarmSink for LogSink {
+    fn send(&mut self, event: &AlarmEvent) {
+        let AlarmEvent {
+            rule,
+            quantity,
+            phase,
+            threshold,
+            value,
+            unit,
+            ..
+        } = event;
+        match event.state {
+            AlarmState::Raised => tracing::warn!(
+                rule,
+                "Alarm raised, {quantity} on {phase} is {value:.1} {unit}, the threshold is {threshold:.1} {unit}"
+            ),
+            AlarmState::Cleared => tracing::info!(
+                rule,
+                "Alarm cleared, {quantity} on {phase} was {value:.1} {unit} at worst"
+            ),
+        }
+    }
+}
+
+/// Publishes events as json to `<topic_prefix>/alarms`, or `<topic_prefix>/<meter_id>/alarms` with more than
+/// one meter like the telegrams
+pub struct MqttSink {
+    pub publisher: MqttPublisher,
+    pub per_meter_topics: bool,
+}
+
+impl AlarmSink for MqttSink {
+    fn send(&mut self, event: &AlarmEvent) {
+        let topic = if self.per_meter_topics {
+            format!("{}/alarms", event.meter_id)
+        } else {
+            "alarms".into()
+        };
+        self.publisher.publish_json(&topic, event);
+    }
+}
+
+/// Sends raised alarms as high priority notifications
+impl AlarmSink for Notifier {
+    fn send(&mut self, event: &AlarmEvent) {
+        let (event_name, priority, title, message) = match event.state {
+            AlarmState::Raised => (
+                "alarm_raised",
+                Priority::High,
+                format!("Alarm {} raised", event.rule),
+                format!(
+                    "{} on {} of meter {} is {:.1} {}, the threshold is {:.1} {}",
+                    event.quantity,
+                    event.phase,
+                    event.meter_id,
+                    event.value,
+                    event.unit,
+                    event.threshold,
+                    event.unit
+                ),
+            ),
+            AlarmState::Cleared => (
+                "alarm_cleared",
+                Priority::Default,
+                format!("Alarm {} cleared", event.rule),
+                format!(
+                    "{} on {} of meter {} was {:.1} {} at worst",
+                    event.quantity, event.phase, event.meter_id, event.value, event.unit
+                ),
+            ),
+        };
+
+        self.notify(
+            Notification::new(event_name, priority, title, message)
+                .field("meter_id", &event.meter_id)
+                .field("rule", &event.rule)
+                .field("quantity", event.quantity)
+                .field("phase", event.phase)
+                .field("value", event.value)
+                .field("threshold", event.threshold)
+                .field("unit", event.unit)
+                .field("raised_time", event.raised_time),
+        );
+    }
+}
+
+/// Hands events to a task, for sinks that have to wait for something
+impl AlarmSink for mpsc::Sender<AlarmEvent> {
+    fn send(&mut self, event: &AlarmEvent) {
+        if let Err(e) = self.try_send(event.clone()) {
+            tracing::error!("Dropped alarm event: {e}");
+        }
+    }
+}
+
+/// Writes the events to the `alarm_events` table, in order and retrying until the database is back.
+///
+/// Alarms only live in memory, so the ones that were still active when the reader stopped are closed first.
+/// Otherwise they'd stay open forever, next to the new row when the alarm is raised again.
+pub async fn run_alarm_writer(pool: PgPool, mut events_rx: mpsc::Receiver<AlarmEvent>) {
+    let mut pending = VecDeque::new();
+    let mut closed_previous = false;
+    let mut failing = false;
+
+    loop {
+        if pending.is_empty() && closed_previous {
+            match events_rx.recv().await {
+                Some(event) => pending.push_back(event),
+                None => return,
+            }
+        }
+        while let Ok(event) = events_rx.try_recv() {
+            pending.push_back(event);
+        }
+
+        if !closed_previous {
+            match close_active_alarms(&pool).await {
+                Ok(()) => closed_previous = true,
+                Err(e) => {
+                    if !failing {
+                        failing = true;
+                        tracing::error!("Could not clear the alarms from before the restart: {e}");
+                    }
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                    continue;
+                }
+            }
+        }
+
+        while let Some(event) = pending.front() {
+            match write_event(&pool, event).await {
+                Ok(()) => {
+                    pending.pop_front();
+                    failing = false;
+                }
+                Err(e) => {
+                    if !failing {
+                        failing = true;
+                        tracing::error!("Could not write alarm event: {e}");
+                    }
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Clears the alarms that are still open in the database, at the time the reader started again
+async fn close_active_alarms(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let closed =
+        sqlx::query!("UPDATE alarm_events SET cleared_time = now() WHERE cleared_time IS NULL")
+            .execute(pool)
+            .await?
+            .rows_affected();
+    if closed > 0 {
+        tracing::info!("Cleared {closed} alarms that were active before the restart");
+    }
+    Ok(())
+}
+
+async fn write_event(pool: &PgPool, event: &AlarmEvent) -> Result<(), sqlx::Error> {
+    match event.state {
+        AlarmState::Raised => {
+            sqlx::query!(
+                "INSERT INTO alarm_events (meter_id, rule, phase, time, quantity, threshold, value)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT DO NOTHING",
+                event.meter_id,
+                event.rule,
+                event.phase,
+                event.raised_time,
+                event.quantity,
+                event.threshold,
+                event.value,
+            )
+            .execute(pool)
+            .await?;
+        }
+        AlarmState::Cleared => {
+            sqlx::query!(
+                "UPDATE alarm_events SET cleared_time = $5, value = $6
+                WHERE meter_id = $1 AND rule = $2 AND phase = $3 AND time = $4",
+                event.meter_id,
+                event.rule,
+                event.phase,
+                event.raised_time,
+                event.time,
+                event.value,
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    #[test]
+    fn raise_and_clear_with_hysteresis() {
+        let rule = AlarmRule {
+            name: "overload".into(),
+            meter: None,
+            quantity: AlarmQuantity::Current,
+            phase: Some(Phase::L1),
+            above: Some(25.0),
+            below: None,
+            duration: Duration::from_secs(10),
+            hysteresis: 2.0,
+        };
+        let mut alarms = MeterAlarms::new("main", &[rule]);
+
+        let start = DateTime::<Utc>::from_timestamp(1_760_000_000, 0).unwrap();
+        let mut states = Vec::new();
+        for (second, current) in [
+            (0, 30.0),
+            // Back below the threshold before the 10 seconds are up, so it starts over
+            (5, 20.0),
+            (6, 30.0),
+            (15, 32.0),
+            (16, 35.0),
+            (17, 24.0),
+            (18, 26.0),
+            (19, 23.0),
+            (20, 30.0),
+        ] {
+            let data = ElectricityData {
+                time: start + TimeDelta::seconds(second),
+                current: [current, 0.0, 0.0],
+                ..Default::default()
+            };
+            for event in alarms.update(&data) {
+                states.push((second, event.state, event.value));
+            }
+        }
+
+        assert_eq!(
+            states,
+            [
+                (16, AlarmState::Raised, 35.0),
+                (19, AlarmState::Cleared, 35.0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use notifier::{Notification, Notifier, Priority};
+use serde::Serialize;
+use settings::BaseloadConfig;
+use sqlx::postgres::PgPool;
+
+/// Nights with data for less than this part of their minutes get no baseload
+const MIN_COVERAGE: f64 = 0.5;
+/// The reference is only set with a baseload on at least this many of the days before
+const MIN_REFERENCE_DAYS: usize = 3;
+
+/// Works out the baseload of every meter after every night into the `baseload_daily` table, from the minute
+/// rollups. Nights that haven't been worked out yet, like all of them on the first run, are caught up on. A night
+/// is only worked out once the minute rollups have gone past its end, so nights the database or the spool was
+/// still catching up on aren't stored with part of their minutes.
+///
+/// The baseload is the lowest minute of consumption in the night: the net power, plus the PV output from the
+/// solar-reader's database for the meter the inverter is behind. A baseload more than `min_increase` above
+/// the median of the days before is flagged, and sent as a `baseload_increased` notification for the last
+/// night.
+pub async fn run_baseload(
+    pool: PgPool,
+    solar_pool: Option<PgPool>,
+    timezone: Tz,
+    meter_ids: Vec<String>,
+    config: BaseloadConfig,
+    notifier: Notifier,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut failing = false;
+
+    loop {
+        interval.tick().await;
+
+        let mut result = Ok(());
+        for meter_id in &meter_ids {
+            let solar_pool = solar_pool
+                .as_ref()
+                .filter(|_| *meter_id == config.solar_meter);
+            result = update_meter(&pool, solar_pool, timezone, meter_id, &config, &notifier).await;
+            if result.is_err() {
+                break;
+            }
+        }
+
+        match result {
+            Ok(()) => failing = false,
+            Err(e) => {
+                if !failing {
+                    failing = true;
+                    tracing::error!("Could not update the baseload: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Works out the nights of a meter that are rolled up and not in `baseload_daily` yet
+async fn update_meter(
+    pool: &PgPool,
+    solar_pool: Option<&PgPool>,
+    timezone: Tz,
+    meter_id: &str,
+    config: &BaseloadConfig,
+    notifier: &Notifier,
+) -> Result<(), sqlx::Error> {
+    let days = sqlx::query!(
+        r#"SELECT
+            COALESCE(
+                (SELECT max(day) + 1 FROM baseload_daily WHERE meter_id = $1),
+                (SELECT (min(time) AT TIME ZONE $2)::DATE FROM electricity_rollup_minute WHERE meter_id = $1)
+            ) AS first_day,
+            (SELECT max(time) FROM electricity_rollup_minute WHERE meter_id = $1) AS rolled_up_until"#,
+        meter_id,
+        timezone.name(),
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let (Some(mut day), Some(rolled_up_until)) = (days.first_day, days.rolled_up_until) else {
+        return Ok(());
+    };
+
+    let now = Utc::now();
+    loop {
+        // The minutes before the last rollup are complete, the last one is still being filled
+        let (start, end) = night(day, timezone, config);
+        if end > rolled_up_until {
+            return Ok(());
+        }
+
+        let net_power: Vec<(DateTime<Utc>, f32)> = sqlx::query!(
+            r#"SELECT time, (power_avg[1] + power_avg[2] + power_avg[3]) AS "power!"
+            FROM electricity_rollup_minute
+            WHERE meter_id = $1 AND time >= $2 AND time < $3"#,
+            meter_id,
+            start,
+            end,
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.time, row.power))
+        .collect();
+
+        // The inverter doesn't report while it's asleep, which is the same as no PV output
+        let solar_power: HashMap<DateTime<Utc>, f32> = match solar_pool {
+            Some(solar_pool) => sqlx::query_as(
+                "SELECT date_trunc('minute', time), (avg(active_power_output) / 1000)::REAL
+                FROM solar_data_points
+                WHERE time >= $1 AND time < $2
+                GROUP BY 1",
+            )
+            .bind(start)
+            .bind(end)
+            .fetch_all(solar_pool)
+            .await?
+            .into_iter()
+            .collect(),
+            None => HashMap::new(),
+        };
+
+        let covered = net_power.len() as f64 >= (end - start).num_minutes() as f64 * MIN_COVERAGE;
+        let lowest = lowest_consumption(&net_power, &solar_power).filter(|_| covered);
+        let baseload = lowest.map(|(_, power)| power);
+
+        let mut previous = sqlx::query_scalar!(
+            r#"SELECT baseload AS "baseload!"
+            FROM baseload_daily
+            WHERE meter_id = $1 AND day < $2 AND day >= $2 - $3::INTEGER AND baseload IS NOT NULL"#,
+            meter_id,
+            day,
+            config.reference_days as i32,
+        )
+        .fetch_all(pool)
+        .await?;
+        let reference = (previous.len() >= MIN_REFERENCE_DAYS.min(config.reference_days as usize))
+            .then(|| median(&mut previous))
+            .flatten();
+        let increased = baseload
+            .zip(reference)
+            .is_some_and(|(baseload, reference)| baseload > reference + config.min_increase);
+
+        sqlx::query!(
+            "INSERT INTO baseload_daily (meter_id, day, minutes, baseload, time, solar_corrected, reference, increased)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (meter_id, day) DO UPDATE SET
+                minutes = EXCLUDED.minutes,
+                baseload = EXCLUDED.baseload,
+                time = EXCLUDED.time,
+                solar_corrected = EXCLUDED.solar_corrected,
+                reference = EXCLUDED.reference,
+                increased = EXCLUDED.increased",
+            meter_id,
+            day,
+            net_power.len() as i32,
+            baseload,
+            lowest.map(|(time, _)| time),
+            solar_pool.is_some(),
+            reference,
+            increased,
+        )
+        .execute(pool)
+        .await?;
+
+        match (baseload, reference) {
+            // Only about last night, not about nights that are caught up on
+            (Some(baseload), Some(reference)) if increased && end > now - TimeDelta::days(1) => {
+                notify_increase(notifier, meter_id, day, baseload, reference);
+            }
+            (Some(baseload), _) => {
+                tracing::debug!(meter = meter_id, "Baseload on {day} was {baseload:.3} kW");
+            }
+            (None, _) => {
+                tracing::debug!(
+                    meter = meter_id,
+                    "Too little data for the baseload on {day}"
+                );
+            }
+        }
+
+        day = day.succ_opt().unwrap();
+    }
+}
+
+fn notify_increase(
+    notifier: &Notifier,
+    meter_id: &str,
+    day: NaiveDate,
+    baseload: f32,
+    reference: f32,
+) {
+    let watts = (baseload * 1000.0).round();
+    let reference_watts = (reference * 1000.0).round();
+    tracing::warn!(
+        meter = meter_id,
+        "The baseload went up to {watts} W last night, from {reference_watts} W usually"
+    );
+    notifier.notify(
+        Notification::new(
+            "baseload_increased",
+            Priority::Default,
+            format!("Baseload of meter {meter_id} went up"),
+            format!(
+                "The always-on consumption was {watts} W last night, {} W more than the usual {reference_watts} W. Maybe something was left on, or a fridge or freezer stopped cycling.",
+                watts - reference_watts
+            ),
+        )
+        .field("meter_id", meter_id)
+        .field("day", day)
+        .field("watts", watts)
+        .field("reference_watts", reference_watts),
+    );
+}
+
+/// The night that ends on `day`, in UTC
+fn night(day: NaiveDate, timezone: Tz, config: &BaseloadConfig) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start_day = if config.night_start_hour > config.night_end_hour {
+        day.pred_opt().unwrap()
+    } else {
+        day
+    };
+    (
+        local_hour(start_day, config.night_start_hour, timezone),
+        local_hour(day, config.night_end_hour, timezone),
+    )
+}
+
+fn local_hour(date: NaiveDate, hour: u32, timezone: Tz) -> DateTime<Utc> {
+    let local_time = |hour| {
+        timezone
+            .from_local_datetime(&date.and_time(NaiveTime::from_hms_opt(hour, 0, 0)?))
+            .earliest()
+    };
+
+    // The hour that is skipped when summer time starts is the next hour
+    local_time(hour)
+        .or_else(|| local_time(hour + 1))
+        .unwrap()
+        .with_timezone(&Utc)
+}
+
+/// The lowest minute of consumption in kW and when, from the net power of every minute and the PV output of
+/// the minutes the inverter reported
+fn lowest_consumption(
+    net_power: &[(DateTime<Utc>, f32)],
+    solar_power: &HashMap<DateTime<Utc>, f32>,
+) -> Option<(DateTime<Utc>, f32)> {
+    net_power
+        .iter()
+        .map(|(time, power)| {
+            let solar = solar_power.get(time).copied().unwrap_or(0.0);
+            (*time, power + solar)
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+fn median(values: &mut [f32]) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f32::total_cmp);
+    let middle = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    })
+}
+
+/// A row of `baseload_daily`
+#[derive(Debug, Serialize)]
+pub struct BaseloadDay {
+    pub day: NaiveDate,
+    pub minutes: i32,
+    pub baseload: Option<f32>,
+    pub time: Option<DateTime<Utc>>,
+    pub solar_corrected: bool,
+    pub reference: Option<f32>,
+    pub increased: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solar_corrected_minimum() {
+        let minute = |m: i64| DateTime::from_timestamp(m * 60, 0).unwrap();
+        let net_power = [
+            (minute(0), 0.15),
+            // Lower on the meter, but the inverter was already supplying some of it
+            (minute(1), 0.05),
+            (minute(2), 0.12),
+        ];
+        let solar_power = HashMap::from([(minute(1), 0.2)]);
+
+        assert_eq!(
+            lowest_consumption(&net_power, &solar_power),
+            Some((minute(2), 0.12))
+        );
+        assert_eq!(median(&mut [0.3, 0.1, 0.2, 0.9]), Some(0.25));
+        assert_eq!(median(&mut []), None);
+    }
+}
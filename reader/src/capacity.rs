@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{
+    DateTime, Datelike, DurationRound, Months, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use notifier::{Notification, Notifier, Priority};
+use p1::{ElectricityData, MaxDemand};
+use sqlx::postgres::PgPool;
+
+/// The capacity tariff bills the average import power over quarter-hours
+const QUARTER: TimeDelta = TimeDelta::minutes(15);
+/// Peaks below this are billed as this, so there's no point in warning about them
+const MINIMUM_BILLED_KW: f32 = 2.5;
+/// Early in a quarter-hour a kettle is enough to make it look like a new peak, so only warn after this
+const WARN_AFTER: TimeDelta = TimeDelta::minutes(5);
+
+/// The current month's peak of every meter, by meter ID
+pub type MonthlyPeaks = Arc<Mutex<HashMap<String, (NaiveDate, MaxDemand)>>>;
+
+/// Keeps the `capacity_peaks` table up to date with the peaks of the current and previous month of every
+/// meter, and shares the current month's peaks with the main loop.
+///
+/// Meters that report their monthly peak (1-0:1.6.0) are trusted. For other meters it's worked out from the
+/// import counters at the start of every quarter-hour in the minute rollups.
+///
+/// When the peak of the current month goes up, a `capacity_peak` notification is sent.
+pub async fn run_capacity_peaks(
+    pool: PgPool,
+    timezone: Tz,
+    meter_ids: Vec<String>,
+    peaks: MonthlyPeaks,
+    notifier: Notifier,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut failing = false;
+
+    loop {
+        interval.tick().await;
+
+        let current_month = month_of(Utc::now(), timezone);
+        let mut result = Ok(());
+        'meters: for meter_id in &meter_ids {
+            // The previous month can still change until its last quarter-hour is rolled up
+            for month in [current_month - Months::new(1), current_month] {
+                match update_peak(&pool, timezone, meter_id, month).await {
+                    Ok(Some(peak)) if month == current_month => {
+                        let previous = peaks
+                            .lock()
+                            .unwrap()
+                            .insert(meter_id.clone(), (month, peak));
+                        // The peak found at startup isn't necessarily new
+                        if matches!(previous, Some((previous_month, previous)) if previous_month == month && peak.kw > previous.kw)
+                        {
+                            notify_peak(&notifier, meter_id, peak, timezone);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        result = Err(e);
+                        break 'meters;
+                    }
+                }
+            }
+        }
+
+        match result {
+            Ok(()) => failing = false,
+            Err(e) => {
+                if !failing {
+                    failing = true;
+                    tracing::error!("Could not update capacity peaks: {e}");
+                }
+            }
+        }
+    }
+}
+
+fn notify_peak(notifier: &Notifier, meter_id: &str, peak: MaxDemand, timezone: Tz) {
+    let local_time = peak.time.with_timezone(&timezone).format("%Y-%m-%d %H:%M");
+    tracing::info!(
+        meter = meter_id,
+        "New capacity peak of {:.2} kW at {local_time}",
+        peak.kw
+    );
+    notifier.notify(
+        Notification::new(
+            "capacity_peak",
+            Priority::Default,
+            format!("New capacity peak for meter {meter_id}"),
+            format!(
+                "The highest quarter-hour this month so far averaged {:.2} kW, at {local_time}",
+                peak.kw
+            ),
+        )
+        .field("meter_id", meter_id)
+        .field("kw", format!("{:.2}", peak.kw))
+        .field("time", local_time),
+    );
+}
+
+/// Sends a `peak_warning` notification for the projected average import returned by [PeakWarning::update]
+pub fn notify_peak_warning(notifier: &Notifier, meter_id: &str, projected: f32) {
+    notifier.notify(
+        Notification::new(
+            "peak_warning",
+            Priority::High,
+            format!("Meter {meter_id} is heading for a new capacity peak"),
+            format!(
+                "This quarter-hour is heading for an average import of {projected:.2} kW, a new peak for the month"
+            ),
+        )
+        .field("meter_id", meter_id)
+        .field("kw", format!("{projected:.2}")),
+    );
+}
+
+/// Works out the peak of a month and stores it
+async fn update_peak(
+    pool: &PgPool,
+    timezone: Tz,
+    meter_id: &str,
+    month: NaiveDate,
+) -> Result<Option<MaxDemand>, sqlx::Error> {
+    let start = local_midnight(month, timezone);
+    let end = local_midnight(month + Months::new(1), timezone);
+
+    // The meter's own peak only goes up during the month, so its last one is the peak of the month
+    let reported = sqlx::query!(
+        r#"SELECT max_demand_month AS "kw!", max_demand_month_time AS "time!"
+        FROM electricity_data_points
+        WHERE meter_id = $1 AND time >= $2 AND time < $3
+            AND max_demand_month IS NOT NULL AND max_demand_month_time IS NOT NULL
+        ORDER BY time DESC
+        LIMIT 1"#,
+        meter_id,
+        start,
+        end,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let (peak, source) = match reported {
+        Some(row) => (
+            MaxDemand {
+                time: row.time,
+                kw: row.kw,
+            },
+            "meter",
+        ),
+        None => {
+            // The energy between the first readings of consecutive quarter-hours, four times per hour
+            let computed = sqlx::query!(
+                r#"WITH quarters AS (
+                    SELECT
+                        time,
+                        kwh_import_total_tarif_low_first + kwh_import_total_tarif_high_first AS kwh,
+                        lag(time) OVER (ORDER BY time) AS previous_time,
+                        lag(kwh_import_total_tarif_low_first + kwh_import_total_tarif_high_first) OVER (ORDER BY time) AS previous_kwh
+                    FROM electricity_rollup_minute
+                    WHERE meter_id = $1 AND time >= $2 AND time <= $3
+                        AND date_bin('15 minutes', time, TIMESTAMPTZ 'epoch') = time
+                )
+                SELECT time AS "time!", ((kwh - previous_kwh) * 4)::REAL AS "kw!"
+                FROM quarters
+                WHERE previous_time = time - INTERVAL '15 minutes'
+                ORDER BY 2 DESC
+                LIMIT 1"#,
+                meter_id,
+                start,
+                end,
+            )
+            .fetch_optional(pool)
+            .await?;
+
+            let Some(row) = computed else {
+                return Ok(None);
+            };
+            (
+                MaxDemand {
+                    time: row.time,
+                    kw: row.kw,
+                },
+                "counters",
+            )
+        }
+    };
+
+    sqlx::query!(
+        "INSERT INTO capacity_peaks (meter_id, month, time, power, source) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (meter_id, month) DO UPDATE SET time = $3, power = $4, source = $5",
+        meter_id,
+        month,
+        peak.time,
+        peak.kw,
+        source,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(Some(peak))
+}
+
+/// Warns when the running quarter-hour of a meter is heading for a new monthly peak, with a `peak_warning`
+/// notification from the main loop
+pub struct PeakWarning {
+    timezone: Tz,
+    peaks: MonthlyPeaks,
+    /// The start of the running quarter-hour, and the time and import counter of its first telegram
+    quarter: Option<(DateTime<Utc>, DateTime<Utc>, f32)>,
+    warned: bool,
+}
+
+impl PeakWarning {
+    pub fn new(timezone: Tz, peaks: MonthlyPeaks) -> Self {
+        Self {
+            timezone,
+            peaks,
+            quarter: None,
+            warned: false,
+        }
+    }
+
+    /// Returns the projected average import in kW when this telegram warned about it, which happens at most once
+    /// per quarter-hour
+    pub fn update(&mut self, meter_id: &str, data: &ElectricityData) -> Option<f32> {
+        let quarter_start = data.time.duration_trunc(QUARTER).unwrap();
+        let kwh = data.kwh_import_total_tarif_low + data.kwh_import_total_tarif_high;
+        if self
+            .quarter
+            .is_none_or(|(start, _, _)| start != quarter_start)
+        {
+            self.quarter = Some((quarter_start, data.time, kwh));
+            self.warned = false;
+        }
+        let (_, first_time, first_kwh) = self.quarter.unwrap();
+
+        let elapsed = data.time - quarter_start;
+        if self.warned || elapsed < WARN_AFTER {
+            return None;
+        }
+
+        // The meter's own average is over the whole quarter-hour so far, ours starts at the first telegram
+        let average = match data.average_demand {
+            Some(average) => average,
+            None if data.time > first_time => {
+                (kwh - first_kwh) * 3600.0 / (data.time - first_time).as_seconds_f32()
+            }
+            None => return None,
+        };
+
+        // Assume the power stays like this for the rest of the quarter-hour
+        let power = (0..3)
+            .map(|i| data.active_powers_import[i] - data.active_powers_export[i])
+            .sum::<f32>()
+            .max(0.0);
+        let elapsed = elapsed.as_seconds_f32();
+        let remaining = QUARTER.as_seconds_f32() - elapsed;
+        let projected = (average * elapsed + power * remaining) / QUARTER.as_seconds_f32();
+
+        let month = month_of(data.time, self.timezone);
+        let month_peak = match data.max_demand_month {
+            Some(peak) => Some(peak.kw),
+            None => self
+                .peaks
+                .lock()
+                .unwrap()
+                .get(meter_id)
+                .filter(|(peak_month, _)| *peak_month == month)
+                .map(|(_, peak)| peak.kw),
+        };
+        let threshold = month_peak.unwrap_or(0.0).max(MINIMUM_BILLED_KW);
+
+        if projected > threshold {
+            self.warned = true;
+            tracing::warn!(
+                "This quarter-hour is heading for an average import of {projected:.2} kW, a new peak for the month above {threshold:.2} kW"
+            );
+            return Some(projected);
+        }
+        None
+    }
+}
+
+/// The first day of the month `time` is in, in `timezone`
+fn month_of(time: DateTime<Utc>, timezone: Tz) -> NaiveDate {
+    time.with_timezone(&timezone)
+        .date_naive()
+        .with_day(1)
+        .unwrap()
+}
+
+fn local_midnight(date: NaiveDate, timezone: Tz) -> DateTime<Utc> {
+    let local_time = |time| {
+        timezone
+            .from_local_datetime(&date.and_time(time))
+            .earliest()
+    };
+
+    // A few timezones skip midnight when summer time starts, the day starts an hour later there
+    local_time(NaiveTime::MIN)
+        .or_else(|| local_time(NaiveTime::from_hms_opt(1, 0, 0).unwrap()))
+        .unwrap()
+        .with_timezone(&Utc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warn_once_per_quarter_hour() {
+        let timezone = chrono_tz::Europe::Brussels;
+        let start = DateTime::<Utc>::from_timestamp(1_759_999_500, 0).unwrap();
+        let peaks = MonthlyPeaks::default();
+        peaks.lock().unwrap().insert(
+            "main".into(),
+            (
+                month_of(start, timezone),
+                MaxDemand {
+                    time: start,
+                    kw: 4.0,
+                },
+            ),
+        );
+        let mut warning = PeakWarning::new(timezone, peaks);
+
+        let mut warnings = Vec::new();
+        for minute in 0..25 {
+            let data = ElectricityData {
+                time: start + TimeDelta::minutes(minute),
+                // 3 kW on average so far...
+                kwh_import_total_tarif_high: 0.05 * minute as f32,
+                // ...but 6 kW now in the first quarter-hour, and still 3 kW in the second
+                active_powers_import: [if minute < 15 { 6.0 } else { 3.0 }, 0.0, 0.0],
+                // Only from here on the meter reports its own monthly peak, which is below the minimum billed
+                max_demand_month: (minute >= 22).then_some(MaxDemand {
+                    time: start,
+                    kw: 2.0,
+                }),
+                ..Default::default()
+            };
+            if let Some(projected) = warning.update("main", &data) {
+                warnings.push((minute, (projected * 100.0).round() / 100.0));
+            }
+        }
+
+        // Not before `WARN_AFTER`, then (3 kW * 5 + 6 kW * 10) / 15 is above the 4 kW peak of the month. In the
+        // next quarter-hour 3 kW is only above the meter's peak, which is billed as the 2.5 kW minimum.
+        assert_eq!(warnings, [(5, 5.0), (22, 3.0)]);
+    }
+}
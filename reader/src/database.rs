@@ -0,0 +1,478 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use notifier::{Notification, Notifier, Priority};
+use p1::{MeterStatus, PowerFailureEvent, SlaveDevice, TelegramData};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPool, PgConnection, Postgres, QueryBuilder};
+use tokio::{sync::Notify, time::Instant};
+use tracing::{Instrument, Span};
+
+use crate::{metrics::METRICS, spool::Spool};
+
+/// Postgres allows at most 65535 bind parameters per query
+const MAX_BIND_PARAMS: usize = 65535;
+/// Bind parameters per row of the electricity and slave inserts in `write_batch`
+const ELECTRICITY_PARAMS: usize = 13;
+const SLAVE_PARAMS: usize = 5;
+/// There's one electricity row per telegram. Slave rows are inserted in chunks, as there can be up to four per
+/// telegram.
+const MAX_BATCH_SIZE: usize = MAX_BIND_PARAMS / ELECTRICITY_PARAMS;
+/// At most this many telegram spans are kept open until their telegrams are written, an hour of telegrams of
+/// one DSMR 5 meter
+const MAX_TELEGRAM_SPANS: usize = 3600;
+
+/// A telegram and the meter it came from, as it goes through the spool
+#[derive(Serialize, Deserialize)]
+pub struct MeterTelegram {
+    /// Missing in entries spooled before there could be more than one meter
+    #[serde(default = "default_meter_id")]
+    pub meter_id: String,
+    #[serde(flatten)]
+    pub data: TelegramData,
+}
+
+fn default_meter_id() -> String {
+    settings::DEFAULT_METER_ID.into()
+}
+
+/// The `telegram` spans of spooled telegrams, which are kept open until the batch with the telegram is committed.
+///
+/// The spool is on disk, so the spans can't go through it. Telegrams spooled before a restart, or while this is
+/// full because the database is down, are written without their span.
+#[derive(Default)]
+pub struct TelegramSpans {
+    spans: Mutex<HashMap<TelegramKey, Span>>,
+}
+
+/// The meter ID and time of a telegram
+type TelegramKey = (String, DateTime<Utc>);
+
+/// Just the key of a spool entry, which can often still be read when the rest can't, like when a NaN was written
+/// as `null`
+#[derive(Deserialize)]
+struct SpooledKey {
+    #[serde(default = "default_meter_id")]
+    meter_id: String,
+    electricity_data: SpooledTime,
+}
+
+#[derive(Deserialize)]
+struct SpooledTime {
+    time: DateTime<Utc>,
+}
+
+impl TelegramSpans {
+    pub fn insert(&self, telegram: &MeterTelegram, span: Span) {
+        let mut spans = self.spans.lock().unwrap();
+        if spans.len() < MAX_TELEGRAM_SPANS {
+            spans.insert(Self::key(telegram), span);
+        }
+    }
+
+    /// Makes `batch_span` follow from the spans of the telegrams in the batch
+    fn link(&self, batch_span: &Span, keys: &[TelegramKey]) {
+        let spans = self.spans.lock().unwrap();
+        for key in keys {
+            if let Some(span) = spans.get(key) {
+                batch_span.follows_from(span);
+            }
+        }
+    }
+
+    /// Closes the spans of the telegrams in the batch
+    fn remove(&self, keys: &[TelegramKey]) {
+        let mut spans = self.spans.lock().unwrap();
+        for key in keys {
+            spans.remove(key);
+        }
+    }
+
+    /// Closes the span of a telegram whose spool entry was skipped, if its key can be read
+    fn remove_skipped(&self, line: &[u8]) {
+        if let Ok(entry) = serde_json::from_slice::<SpooledKey>(line) {
+            self.remove(&[(entry.meter_id, entry.electricity_data.time)]);
+        }
+    }
+
+    fn key(telegram: &MeterTelegram) -> TelegramKey {
+        (
+            telegram.meter_id.clone(),
+            telegram.data.electricity_data.time,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    /// Write as soon as this many telegrams are waiting
+    pub size: usize,
+    /// Write whatever is waiting at least this often
+    pub interval: Duration,
+}
+
+/// Writes everything that ends up in the spool to the database, in order.
+///
+/// When the database is unreachable, the data stays in the spool and is retried later.
+pub async fn run_database_writer(
+    pool: PgPool,
+    spool: Arc<Mutex<Spool<MeterTelegram>>>,
+    telegram_spans: Arc<TelegramSpans>,
+    new_data: Arc<Notify>,
+    batch_config: BatchConfig,
+    notifier: Notifier,
+) {
+    let batch_size = batch_config.size.clamp(1, MAX_BATCH_SIZE);
+
+    let mut writer = DatabaseWriter::default();
+    let mut migrated = false;
+    let mut failing = false;
+    let mut sync_failing = false;
+    let mut flush_deadline = Instant::now();
+
+    loop {
+        // Only write partial batches, and sync the spool, when the interval has passed
+        let flush = match tokio::time::timeout_at(flush_deadline, new_data.notified()).await {
+            Ok(()) => false,
+            Err(_) => {
+                flush_deadline = Instant::now() + batch_config.interval;
+                true
+            }
+        };
+
+        if flush {
+            match sync_spool(&spool).await {
+                Ok(()) => sync_failing = false,
+                Err(e) => {
+                    METRICS.spool_errors.inc();
+                    if !sync_failing {
+                        sync_failing = true;
+                        tracing::error!("Could not sync the spool to disk: {e}");
+                    }
+                }
+            }
+        }
+
+        let result = async {
+            if !migrated {
+                tracing::info!("Running database migrations");
+                sqlx::migrate!().run(&pool).await?;
+                migrated = true;
+            }
+
+            writer
+                .write_pending(&pool, &spool, &telegram_spans, batch_size, flush)
+                .await
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                if failing {
+                    failing = false;
+                    tracing::info!("Database is reachable again, caught up with the spool");
+                    notifier.notify(Notification::new(
+                        "database_recovered",
+                        Priority::Default,
+                        "Database is back",
+                        "The reader caught up with the telegrams it spooled while the database was unreachable",
+                    ));
+                }
+            }
+            Err(e) => {
+                if !failing {
+                    failing = true;
+                    tracing::warn!(
+                        "Could not write to database, spooling to disk until it's back: {e}"
+                    );
+                    notifier.notify(
+                        Notification::new(
+                            "database_failed",
+                            Priority::High,
+                            "Database is unreachable",
+                            format!(
+                                "The reader is spooling telegrams to disk until it's back: {e}"
+                            ),
+                        )
+                        .field("error", &e),
+                    );
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                flush_deadline = Instant::now();
+            }
+        }
+    }
+}
+
+/// Syncs what was pushed to the spool since the last sync to disk.
+///
+/// This happens once per batch interval instead of for every telegram, and on a blocking thread, so a slow SD card
+/// doesn't hold up the rest of the reader.
+async fn sync_spool(spool: &Mutex<Spool<MeterTelegram>>) -> io::Result<()> {
+    let Some(file) = spool.lock().unwrap().unsynced()? else {
+        return Ok(());
+    };
+
+    tokio::task::spawn_blocking(move || file.sync_data())
+        .await
+        .map_err(io::Error::other)?
+}
+
+#[derive(Default, Clone)]
+struct DatabaseWriter {
+    meters: HashMap<String, LastWritten>,
+}
+
+/// The slow changing parts of a meter's last telegram
+#[derive(Default, Clone)]
+struct LastWritten {
+    status: Option<MeterStatus>,
+    slave_devices: [Option<SlaveDevice>; 4],
+    power_failure_events: Vec<PowerFailureEvent>,
+}
+
+impl DatabaseWriter {
+    /// Writes all full batches in the spool, and also the last partial one if `flush` is set
+    async fn write_pending(
+        &mut self,
+        pool: &PgPool,
+        spool: &Mutex<Spool<MeterTelegram>>,
+        telegram_spans: &TelegramSpans,
+        batch_size: usize,
+        flush: bool,
+    ) -> Result<(), Error> {
+        loop {
+            let entries = spool
+                .lock()
+                .unwrap()
+                .peek(batch_size, |line| telegram_spans.remove_skipped(line))?;
+            if entries.is_empty() || (entries.len() < batch_size && !flush) {
+                return Ok(());
+            }
+
+            let offset = entries.last().unwrap().1;
+            let batch: Vec<_> = entries.into_iter().map(|(data, _)| data).collect();
+            let span = tracing::info_span!(
+                "database_write",
+                telegrams = batch.len(),
+                first = %batch.first().unwrap().data.electricity_data.time,
+                last = %batch.last().unwrap().data.electricity_data.time,
+            );
+            let keys: Vec<_> = batch.iter().map(TelegramSpans::key).collect();
+            telegram_spans.link(&span, &keys);
+
+            // Changes to the writer state are only kept when the transaction goes through
+            let mut writer = self.clone();
+            let timer = METRICS.database_write_duration.start_timer();
+            async {
+                let mut tx = pool.begin().await?;
+                writer.write_batch(&mut tx, batch).await?;
+                tx.commit().await?;
+                tracing::debug!("Committed batch");
+                Ok::<_, Error>(())
+            }
+            .instrument(span)
+            .await?;
+            timer.observe_duration();
+
+            *self = writer;
+            spool.lock().unwrap().commit(offset)?;
+            telegram_spans.remove(&keys);
+        }
+    }
+
+    /// Writes a batch of telegrams. Everything is idempotent, so it's fine to write the same telegram twice.
+    async fn write_batch(
+        &mut self,
+        conn: &mut PgConnection,
+        batch: Vec<MeterTelegram>,
+    ) -> Result<(), Error> {
+        let mut electricity_query = QueryBuilder::<Postgres>::new(
+            "insert into electricity_data_points (meter_id, time, kwh_import_total_tarif_low, kwh_import_total_tarif_high, kwh_export_total_tarif_low, kwh_export_total_tarif_high, voltages, active_powers_import, active_powers_export, received_time, average_demand, max_demand_month, max_demand_month_time) ",
+        );
+        electricity_query.push_values(&batch, |mut row, telegram| {
+            let electricity_data = &telegram.data.electricity_data;
+            row.push_bind(&telegram.meter_id)
+                .push_bind(electricity_data.time)
+                .push_bind(electricity_data.kwh_import_total_tarif_low)
+                .push_bind(electricity_data.kwh_import_total_tarif_high)
+                .push_bind(electricity_data.kwh_export_total_tarif_low)
+                .push_bind(electricity_data.kwh_export_total_tarif_high)
+                .push_bind(electricity_data.voltages)
+                .push_bind(electricity_data.active_powers_import)
+                .push_bind(electricity_data.active_powers_export)
+                .push_bind(electricity_data.received_time)
+                .push_bind(electricity_data.average_demand)
+                .push_bind(electricity_data.max_demand_month.map(|peak| peak.kw))
+                .push_bind(electricity_data.max_demand_month.map(|peak| peak.time));
+        });
+        electricity_query.push(" ON CONFLICT DO NOTHING");
+        electricity_query.build().execute(&mut *conn).await?;
+
+        let slave_rows = batch
+            .iter()
+            .flat_map(|telegram| {
+                let data = &telegram.data;
+                data.slave_data
+                    .iter()
+                    .zip(&data.slave_devices)
+                    .enumerate()
+                    .filter_map(|(i, (slave_data, slave_device))| {
+                        Some((
+                            &telegram.meter_id,
+                            i as i16,
+                            (*slave_data)?,
+                            slave_device.as_ref(),
+                        ))
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        for slave_rows in slave_rows.chunks(MAX_BIND_PARAMS / SLAVE_PARAMS) {
+            let mut slave_query = QueryBuilder::<Postgres>::new(
+                "insert into slave_data_points (meter_id, time, id, value, equipment_identifier) ",
+            );
+            slave_query.push_values(
+                slave_rows,
+                |mut row, (meter_id, i, slave_data, slave_device)| {
+                    row.push_bind(*meter_id)
+                        .push_bind(slave_data.time)
+                        .push_bind(*i)
+                        .push_bind(slave_data.value)
+                        .push_bind(slave_device.map(|device| device.equipment_identifier.clone()));
+                },
+            );
+            slave_query.push(" ON CONFLICT DO NOTHING");
+            slave_query.build().execute(&mut *conn).await?;
+        }
+
+        for telegram in batch {
+            let last_written = self.meters.entry(telegram.meter_id.clone()).or_default();
+            last_written
+                .write_changes(&mut *conn, &telegram.meter_id, telegram.data)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl LastWritten {
+    /// Writes the slow changing parts of a telegram, if they changed
+    async fn write_changes(
+        &mut self,
+        conn: &mut PgConnection,
+        meter_id: &str,
+        data: TelegramData,
+    ) -> Result<(), Error> {
+        let TelegramData {
+            electricity_data,
+            slave_devices,
+            status,
+            power_failure_events,
+            ..
+        } = data;
+
+        for (i, slave_device) in slave_devices.iter().enumerate() {
+            if let Some(slave_device) = slave_device {
+                if self.slave_devices[i].as_ref() != Some(slave_device) {
+                    // The name is left alone on conflict so it can be changed by hand
+                    sqlx::query!(
+                        "insert into slave_devices (equipment_identifier, channel, device_type, name, unit, updated, meter_id)
+                        values($1, $2, $3, $4, $5, $6, $7)
+                        ON CONFLICT (equipment_identifier) DO UPDATE
+                        SET channel = $2, device_type = $3, unit = $5, updated = $6, meter_id = $7",
+                        slave_device.equipment_identifier,
+                        i as i16,
+                        slave_device.device_type,
+                        slave_device.name(),
+                        slave_device.unit(),
+                        electricity_data.time,
+                        meter_id,
+                    )
+                    .execute(&mut *conn)
+                    .await?;
+                }
+            }
+        }
+
+        if self.status.as_ref() != Some(&status) {
+            sqlx::query!(
+                "insert into meter_status_points (meter_id, time, equipment_identifier, tariff_indicator, power_failures, long_power_failures, voltage_sags, voltage_swells, text_message)
+                values($1, $2, $3, $4, $5, $6, $7, $8, $9) ON CONFLICT DO NOTHING",
+                meter_id,
+                electricity_data.time,
+                status.equipment_identifier,
+                status.tariff_indicator,
+                status.power_failures,
+                status.long_power_failures,
+                &status.voltage_sags,
+                &status.voltage_swells,
+                status.text_message,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        if self.power_failure_events != power_failure_events {
+            for event in power_failure_events.iter() {
+                sqlx::query!(
+                    "insert into power_failure_events (meter_id, end_time, duration_seconds) values($1, $2, $3) ON CONFLICT DO NOTHING",
+                    meter_id,
+                    event.end_time,
+                    event.duration_seconds,
+                )
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+
+        self.slave_devices = slave_devices;
+        self.status = Some(status);
+        self.power_failure_events = power_failure_events;
+
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("Migrate error: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipped_entries_close_their_span() {
+        let spans = TelegramSpans::default();
+        let time = "2026-10-18T12:00:00Z".parse().unwrap();
+        for meter_id in ["main", "garage"] {
+            spans
+                .spans
+                .lock()
+                .unwrap()
+                .insert((meter_id.into(), time), Span::none());
+        }
+
+        // A NaN is written as null, which can't be read back as a float
+        spans.remove_skipped(
+            br#"{"meter_id":"garage","electricity_data":{"time":"2026-10-18T12:00:00Z","voltages":[null,230.0,230.0]}}"#,
+        );
+        // Entries from before there could be more than one meter are the default meter's
+        spans.remove_skipped(br#"{"electricity_data":{"time":"2026-10-18T12:00:00Z"}}"#);
+
+        assert!(spans.spans.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use sqlx::postgres::PgPool;
+
+use crate::pending::{run_pending_writer, Merge, Pending};
+
+/// What happened to one telegram on the P1 link
+#[derive(Debug, Clone, Copy)]
+pub enum LinkEvent {
+    /// Parsed, with this many objects that couldn't be parsed
+    Telegram {
+        object_errors: u32,
+    },
+    CrcError,
+    FramingError,
+    DataError,
+}
+
+/// Counts of one hour, see the `p1_link_quality` table
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    telegrams: i32,
+    crc_errors: i32,
+    framing_errors: i32,
+    data_errors: i32,
+    object_errors: i32,
+}
+
+impl Merge for Counts {
+    fn merge(&mut self, other: Counts) {
+        self.telegrams += other.telegrams;
+        self.crc_errors += other.crc_errors;
+        self.framing_errors += other.framing_errors;
+        self.data_errors += other.data_errors;
+        self.object_errors += other.object_errors;
+    }
+}
+
+/// Counts telegrams and errors per meter and hour until they're written to the database
+#[derive(Debug, Default)]
+pub struct LinkQuality {
+    hours: Arc<Pending<(String, DateTime<Utc>), Counts>>,
+}
+
+impl LinkQuality {
+    pub fn record(&self, meter_id: &str, event: LinkEvent) {
+        let hour = Utc::now().duration_trunc(TimeDelta::hours(1)).unwrap();
+        let mut counts = Counts::default();
+
+        match event {
+            LinkEvent::Telegram { object_errors } => {
+                counts.telegrams = 1;
+                counts.object_errors = object_errors as i32;
+            }
+            LinkEvent::CrcError => counts.crc_errors = 1,
+            LinkEvent::FramingError => counts.framing_errors = 1,
+            LinkEvent::DataError => counts.data_errors = 1,
+        }
+
+        self.hours.add((meter_id.into(), hour), counts);
+    }
+}
+
+/// Adds the counts to the `p1_link_quality` table every minute.
+///
+/// Counts are added to what's already there, so restarts in the middle of an hour don't lose anything.
+pub async fn run_link_quality_writer(pool: PgPool, link_quality: Arc<LinkQuality>) {
+    run_pending_writer(
+        pool,
+        link_quality.hours.clone(),
+        "link quality",
+        write_counts,
+    )
+    .await;
+}
+
+async fn write_counts(
+    pool: &PgPool,
+    hours: &BTreeMap<(String, DateTime<Utc>), Counts>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    for ((meter_id, hour), counts) in hours {
+        sqlx::query!(
+            "INSERT INTO p1_link_quality (meter_id, time, telegrams, crc_errors, framing_errors, data_errors, object_errors)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (meter_id, time) DO UPDATE SET
+                telegrams = p1_link_quality.telegrams + EXCLUDED.telegrams,
+                crc_errors = p1_link_quality.crc_errors + EXCLUDED.crc_errors,
+                framing_errors = p1_link_quality.framing_errors + EXCLUDED.framing_errors,
+                data_errors = p1_link_quality.data_errors + EXCLUDED.data_errors,
+                object_errors = p1_link_quality.object_errors + EXCLUDED.object_errors",
+            meter_id,
+            hour,
+            counts.telegrams,
+            counts.crc_errors,
+            counts.framing_errors,
+            counts.data_errors,
+            counts.object_errors,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
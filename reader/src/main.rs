@@ -1,50 +1,299 @@
 #![allow(clippy::type_complexity)]
 
-use std::{env, error::Error, io::Read, sync::Arc, time::Duration};
+use std::{
+    error::Error,
+    io::{BufReader, Read},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use chrono::{TimeZone, Utc};
-use dsmr5::{Tariff, Telegram};
-use sqlx::postgres::PgPool;
-use tokio::sync::mpsc;
+use alarms::{AlarmSink, MeterAlarms};
+use capacity::{MonthlyPeaks, PeakWarning};
+use chrono_tz::Tz;
+use database::MeterTelegram;
+use link_quality::{LinkEvent, LinkQuality};
+use notifier::{Notification, Notifier, Priority};
+use p1::{telegram_to_data, TelegramData};
+use phases::PhaseStats;
+use rate_limit::RateLimiter;
+use spool::Spool;
+use sqlx::postgres::PgPoolOptions;
+use tokio::sync::{mpsc, watch, Notify};
+use tracing::Span;
+
+mod alarms;
+mod baseload;
+mod capacity;
+mod database;
+mod http;
+mod link_quality;
+mod metrics;
+mod mqtt;
+mod pending;
+mod phases;
+mod price_import;
+mod rate_limit;
+mod retention;
+mod rollup;
+mod spool;
+
+/// Repeated errors of the same kind are logged at most this often
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// Meters send a telegram every second, so this long without one means something is wrong
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenvy::dotenv().ok();
+    let config = settings::ReaderConfig::load()?;
+    init_logging(&config.log);
 
-    let grid_meter_data = Arc::new(std::sync::Mutex::new(
-        grid_meter::InstantaneousData::default(),
-    ));
-    let grid_meter_address = env::var("GRID_METER_ADDRESS")?.parse().unwrap();
-    tokio::spawn({
-        let grid_meter_data = grid_meter_data.clone();
-        async move {
-            grid_meter::run_grid_meter_server(
-                grid_meter_address,
-                grid_meter_data,
-                grid_meter::MeasuringSystem::Setup3PN,
-                b"BY24600320011\0",
-            )
-            .await
-            .unwrap();
-        }
-    });
+    let timezone = config.meter_timezone;
+    let notifier = Notifier::start(config.notifications);
+    let meter_ids: Vec<String> = config.meters.iter().map(|meter| meter.id.clone()).collect();
 
     let (data_tx, mut data_rx) = mpsc::channel(64);
+    let link_quality = Arc::new(LinkQuality::default());
+    let mut meters = Vec::new();
+    let mut latest_rxs = Vec::new();
+    let monthly_peaks = MonthlyPeaks::default();
+
+    for (index, meter_config) in config.meters.iter().enumerate() {
+        let grid_meter_data = meter_config.grid_meter.clone().map(|grid_meter_config| {
+            let grid_meter_data = Arc::new(std::sync::Mutex::new(
+                grid_meter::InstantaneousData::default(),
+            ));
+            tokio::spawn({
+                let grid_meter_data = grid_meter_data.clone();
+                async move {
+                    grid_meter::run_grid_meter_server(
+                        grid_meter_config.address,
+                        grid_meter_data,
+                        grid_meter_config.measuring_system,
+                        grid_meter_config.serial_number,
+                    )
+                    .await
+                    .unwrap();
+                }
+            });
+            grid_meter_data
+        });
+
+        tracing::info!(
+            "Spawning serial port reader for meter {} on {}",
+            meter_config.id,
+            meter_config.serial_port
+        );
+        let serial_port = serialport::new(&meter_config.serial_port, meter_config.baud_rate);
+        std::thread::spawn({
+            let meter_id = meter_config.id.clone();
+            let data_tx = data_tx.clone();
+            let link_quality = link_quality.clone();
+            move || {
+                serial_port_reader(
+                    serial_port,
+                    meter_id,
+                    index,
+                    data_tx,
+                    timezone,
+                    link_quality,
+                )
+            }
+        });
+
+        let (latest_tx, latest_rx) = watch::channel(None);
+        latest_rxs.push((meter_config.id.clone(), latest_rx));
+        meters.push(Meter {
+            id: meter_config.id.clone(),
+            grid_meter_data,
+            latest_tx,
+            clock_drifting: false,
+            peak_warning: config
+                .capacity_tariff
+                .then(|| PeakWarning::new(timezone, monthly_peaks.clone())),
+            alarms: MeterAlarms::new(&meter_config.id, &config.alarms),
+            last_telegram: tokio::time::Instant::now(),
+            stalled: false,
+        });
+    }
+    // Only the serial port readers hold a sender, so the main loop notices when they are all gone
+    drop(data_tx);
+
+    tracing::info!("Opening spool in {}", config.spool_dir.display());
+    let spool = Arc::new(Mutex::new(Spool::open(&config.spool_dir)?));
+    let telegram_spans = Arc::new(database::TelegramSpans::default());
+    let new_data = Arc::new(Notify::new());
+
+    let batch_config = database::BatchConfig {
+        size: config.batch_size,
+        interval: config.batch_interval,
+    };
+
+    tracing::info!("Spawning database writer");
+    let pool = PgPoolOptions::new().connect_lazy(&config.database_url)?;
+    tokio::spawn(database::run_database_writer(
+        pool.clone(),
+        spool.clone(),
+        telegram_spans.clone(),
+        new_data.clone(),
+        batch_config,
+        notifier.clone(),
+    ));
+
+    tokio::spawn(rollup::run_rollups(
+        pool.clone(),
+        timezone,
+        meter_ids.clone(),
+        config.tariff,
+    ));
+    if let Some(prices_dir) = config.prices_dir {
+        tracing::info!("Importing day-ahead prices from {}", prices_dir.display());
+        tokio::spawn(price_import::run_price_import(pool.clone(), prices_dir));
+    }
+    if config.capacity_tariff {
+        tracing::info!("Tracking the monthly capacity tariff peaks");
+        tokio::spawn(capacity::run_capacity_peaks(
+            pool.clone(),
+            timezone,
+            meter_ids.clone(),
+            monthly_peaks,
+            notifier.clone(),
+        ));
+    }
+
+    let solar_pool = config
+        .baseload
+        .solar_database_url
+        .as_deref()
+        .map(|url| PgPoolOptions::new().connect_lazy(url))
+        .transpose()?;
+    tokio::spawn(baseload::run_baseload(
+        pool.clone(),
+        solar_pool,
+        timezone,
+        meter_ids.clone(),
+        config.baseload,
+        notifier.clone(),
+    ));
+
+    tokio::spawn(link_quality::run_link_quality_writer(
+        pool.clone(),
+        link_quality,
+    ));
 
-    println!("Spawning serial port reader");
-    std::thread::spawn(|| serial_port_reader(data_tx));
+    let phase_stats = Arc::new(PhaseStats::default());
+    tokio::spawn(phases::run_phase_stats_writer(
+        pool.clone(),
+        phase_stats.clone(),
+    ));
+
+    if let Some(retention_days) = config.raw_retention_days {
+        let retention_config = retention::RetentionConfig {
+            max_age: chrono::TimeDelta::days(retention_days.into()),
+            dry_run: config.retention_dry_run,
+        };
+        tracing::info!("Deleting raw electricity data older than {retention_days} days");
+        tokio::spawn(retention::run_retention(
+            pool.clone(),
+            retention_config,
+            meter_ids.clone(),
+        ));
+    }
+
+    if let Some(http_address) = config.http_address {
+        tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                http::run_http_server(http_address, pool, latest_rxs)
+                    .await
+                    .unwrap();
+            }
+        });
+    }
+
+    let per_meter_topics = meters.len() > 1;
+    let mqtt_publisher = config.mqtt.map(mqtt_publisher::MqttPublisher::start);
+    let mut telegram_publisher = mqtt_publisher
+        .clone()
+        .map(|publisher| mqtt::TelegramPublisher::new(publisher, per_meter_topics));
+
+    let mut alarm_sinks: Vec<Box<dyn AlarmSink>> = Vec::new();
+    if !config.alarms.is_empty() {
+        tracing::info!("Checking {} alarm rules", config.alarms.len());
+        alarm_sinks.push(Box::new(alarms::LogSink));
+
+        let (events_tx, events_rx) = mpsc::channel(64);
+        tokio::spawn(alarms::run_alarm_writer(pool.clone(), events_rx));
+        alarm_sinks.push(Box::new(events_tx));
+
+        if let Some(publisher) = mqtt_publisher {
+            alarm_sinks.push(Box::new(alarms::MqttSink {
+                publisher,
+                per_meter_topics,
+            }));
+        }
+        alarm_sinks.push(Box::new(notifier.clone()));
+    }
 
-    println!("Connecting to database");
-    let pool = PgPool::connect(&env::var("DATABASE_URL")?).await?;
-    println!("Running database migrations");
-    sqlx::migrate!().run(&pool).await?;
-    println!("Ready");
+    let max_clock_drift = chrono::TimeDelta::from_std(config.max_clock_drift)?;
+    tracing::info!("Ready");
 
+    let mut stall_check = tokio::time::interval(STALL_TIMEOUT / 4);
+    let mut spool_failing = false;
     loop {
-        let (electricity_data, slave_data) = data_rx.recv().await.unwrap();
+        let (index, data, span): (usize, TelegramData, Span) = tokio::select! {
+            received = data_rx.recv() => received.unwrap(),
+            _ = stall_check.tick() => {
+                check_stalls(&mut meters, &notifier);
+                continue;
+            }
+        };
+        let _enter = span.enter();
+        let meter = &mut meters[index];
+        let electricity_data = &data.electricity_data;
+
+        meter.last_telegram = tokio::time::Instant::now();
+        if meter.stalled {
+            meter.stalled = false;
+            tracing::info!("Receiving telegrams again");
+            notifier.notify(
+                Notification::new(
+                    "p1_resumed",
+                    Priority::Default,
+                    format!("P1 meter {} is back", meter.id),
+                    format!("Receiving telegrams from meter {} again", meter.id),
+                )
+                .field("meter_id", &meter.id),
+            );
+        }
+
+        let clock_drift = electricity_data.received_time - electricity_data.time;
+        if (clock_drift.abs() > max_clock_drift) != meter.clock_drifting {
+            meter.clock_drifting = !meter.clock_drifting;
+            if meter.clock_drifting {
+                tracing::warn!("Meter clock is off by {clock_drift} compared to our own clock");
+            } else {
+                tracing::info!("Meter clock is back in sync, off by {clock_drift}");
+            }
+        }
+
+        metrics::METRICS.update(&meter.id, electricity_data);
+        phase_stats.record(&meter.id, timezone, electricity_data);
+
+        if let Some(peak_warning) = &mut meter.peak_warning {
+            if let Some(projected) = peak_warning.update(&meter.id, electricity_data) {
+                capacity::notify_peak_warning(&notifier, &meter.id, projected);
+            }
+        }
+
+        for event in meter.alarms.update(electricity_data) {
+            for sink in &mut alarm_sinks {
+                sink.send(&event);
+            }
+        }
 
         #[rustfmt::skip]
-        {
+        if let Some(grid_meter_data) = &meter.grid_meter_data {
             let mut grid_meter_data = grid_meter_data.lock().unwrap();
             grid_meter_data.v_l1_n = (electricity_data.voltages[0] * 10.0).round() as i32;
             grid_meter_data.v_l2_n = (electricity_data.voltages[1] * 10.0).round() as i32;
@@ -61,50 +310,132 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
             grid_meter_data.kwh_plus_total = ((electricity_data.kwh_import_total_tarif_high + electricity_data.kwh_import_total_tarif_low) * 10.0).round() as i32;
             grid_meter_data.kwh_neg_total = ((electricity_data.kwh_export_total_tarif_high + electricity_data.kwh_export_total_tarif_low) * 10.0).round() as i32;
+            tracing::debug!("Updated grid meter");
         };
 
-        sqlx::query!(
-            "insert into electricity_data_points values($1, $2, $3, $4, $5, $6, $7, $8)",
-            electricity_data.time,
-            electricity_data.kwh_import_total_tarif_low,
-            electricity_data.kwh_import_total_tarif_high,
-            electricity_data.kwh_export_total_tarif_low,
-            electricity_data.kwh_export_total_tarif_high,
-            &electricity_data.voltages,
-            &electricity_data.active_powers_import,
-            &electricity_data.active_powers_export,
-        )
-        .execute(&pool)
-        .await?;
-
-        for (i, slave_data) in slave_data.into_iter().enumerate() {
-            if let Some(slave_data) = slave_data {
-                sqlx::query!(
-                    "insert into slave_data_points values($1, $2, $3) ON CONFLICT DO NOTHING",
-                    slave_data.time,
-                    i as i16,
-                    slave_data.value,
-                )
-                .execute(&pool)
-                .await?;
+        if let Some(telegram_publisher) = &mut telegram_publisher {
+            telegram_publisher.publish(&meter.id, &data);
+            tracing::debug!("Published to MQTT");
+        }
+
+        let telegram = MeterTelegram {
+            meter_id: meter.id.clone(),
+            data,
+        };
+        let pushed = spool.lock().unwrap().push(&telegram);
+        match pushed {
+            Ok(()) => {
+                spool_failing = false;
+                telegram_spans.insert(&telegram, span.clone());
+                new_data.notify_one();
+                tracing::debug!("Spooled for the database");
+            }
+            Err(e) => {
+                metrics::METRICS.spool_errors.inc();
+                if !spool_failing {
+                    spool_failing = true;
+                    tracing::error!(
+                        "Could not spool telegrams, they won't end up in the database: {e}"
+                    );
+                }
             }
         }
+
+        meter.latest_tx.send_replace(Some(Arc::new(telegram.data)));
     }
 }
 
-fn serial_port_reader(data_tx: mpsc::Sender<(ElectricityData, [Option<SlaveData>; 4])>) {
-    let port = serialport::new("/dev/ttyUSB0", 115_200)
+/// What the main loop keeps per meter
+struct Meter {
+    id: String,
+    /// Only set when the meter is exposed as a grid meter
+    grid_meter_data: Option<Arc<Mutex<grid_meter::InstantaneousData>>>,
+    latest_tx: watch::Sender<Option<Arc<TelegramData>>>,
+    clock_drifting: bool,
+    /// Only set when the capacity tariff is tracked
+    peak_warning: Option<PeakWarning>,
+    alarms: MeterAlarms,
+    /// When the last telegram came in, or when the reader started
+    last_telegram: tokio::time::Instant,
+    /// No telegram came in for `STALL_TIMEOUT`, which was notified
+    stalled: bool,
+}
+
+/// Notifies about meters that stopped sending telegrams
+fn check_stalls(meters: &mut [Meter], notifier: &Notifier) {
+    for meter in meters {
+        let silent = meter.last_telegram.elapsed();
+        if meter.stalled || silent < STALL_TIMEOUT {
+            continue;
+        }
+
+        meter.stalled = true;
+        tracing::warn!(meter = meter.id, "No telegrams for {}s", silent.as_secs());
+        notifier.notify(
+            Notification::new(
+                "p1_stalled",
+                Priority::High,
+                format!("P1 meter {} stalled", meter.id),
+                format!(
+                    "No telegrams from meter {} for {} seconds",
+                    meter.id,
+                    silent.as_secs()
+                ),
+            )
+            .field("meter_id", &meter.id)
+            .field("seconds", silent.as_secs()),
+        );
+    }
+}
+
+/// Reads the telegrams of one meter, which are sent on tagged with `index`
+fn serial_port_reader(
+    serial_port: serialport::SerialPortBuilder,
+    meter_id: String,
+    index: usize,
+    data_tx: mpsc::Sender<(usize, TelegramData, Span)>,
+    timezone: Tz,
+    link_quality: Arc<LinkQuality>,
+) {
+    // Everything logged about the meter's telegrams, also in the rest of the reader, is in this span
+    let _meter_span = tracing::info_span!("meter", id = %meter_id).entered();
+
+    let port = serial_port
         .timeout(Duration::from_millis(2000))
         .open()
         .expect("Failed to open port");
 
-    let reader = dsmr5::Reader::new(port.bytes());
+    let mut reader = dsmr5::Reader::new(BufReader::new(port).bytes());
+
+    let mut read_errors = RateLimiter::new(ERROR_LOG_INTERVAL);
+    let mut parse_errors = RateLimiter::new(ERROR_LOG_INTERVAL);
+    let mut data_errors = RateLimiter::new(ERROR_LOG_INTERVAL);
+    let mut send_errors = RateLimiter::new(ERROR_LOG_INTERVAL);
 
-    for readout in reader {
+    loop {
+        // Follows the telegram from reading through parsing and the main loop, until the batch it is written to
+        // the database in is committed. That batch's `database_write` span follows from it.
+        let span = tracing::info_span!("telegram", meter_time = tracing::field::Empty);
+        let _enter = span.enter();
+
+        let Some(readout) = reader.next() else {
+            break;
+        };
         let readout = match readout {
             Ok(readout) => readout,
             Err(e) => {
-                println!("Read error: {e:?}");
+                // A timeout only means nothing was sent, which shows as missing telegrams
+                if !matches!(&e, dsmr5::ReaderError::IOError(io) if io.kind() == std::io::ErrorKind::TimedOut)
+                {
+                    link_quality.record(&meter_id, LinkEvent::FramingError);
+                }
+                if let Some(suppressed) = read_errors.check() {
+                    tracing::warn!(suppressed, "Read error: {e:?}");
+                }
+                metrics::METRICS
+                    .telegram_errors
+                    .with_label_values(&[&meter_id, "read"])
+                    .inc();
                 continue;
             }
         };
@@ -112,108 +443,77 @@ fn serial_port_reader(data_tx: mpsc::Sender<(ElectricityData, [Option<SlaveData>
         let telegram = match readout.to_telegram() {
             Ok(telegram) => telegram,
             Err(e) => {
-                println!("Parse error: {e:?}");
+                let kind = match e {
+                    dsmr5::Error::InvalidChecksum => {
+                        link_quality.record(&meter_id, LinkEvent::CrcError);
+                        "crc"
+                    }
+                    _ => {
+                        link_quality.record(&meter_id, LinkEvent::FramingError);
+                        "parse"
+                    }
+                };
+                if let Some(suppressed) = parse_errors.check() {
+                    tracing::warn!(suppressed, kind, "Parse error: {e:?}");
+                }
+                metrics::METRICS
+                    .telegram_errors
+                    .with_label_values(&[&meter_id, kind])
+                    .inc();
                 continue;
             }
         };
 
-        let data = match telegram_to_data(telegram) {
+        let object_errors = telegram.objects().filter(Result::is_err).count() as u32;
+        let data = match telegram_to_data(telegram, &readout, timezone) {
             Ok(val) => val,
             Err(e) => {
-                println!("Getting data error: {e:?}");
+                link_quality.record(&meter_id, LinkEvent::DataError);
+                if let Some(suppressed) = data_errors.check() {
+                    tracing::warn!(suppressed, "Getting data error: {e:?}");
+                }
+                metrics::METRICS
+                    .telegram_errors
+                    .with_label_values(&[&meter_id, "data"])
+                    .inc();
                 continue;
             }
         };
+        metrics::METRICS
+            .telegrams_parsed
+            .with_label_values(&[&meter_id])
+            .inc();
+        // Only for telegrams that made it, like in the link quality, so a rejected one isn't counted twice
+        metrics::METRICS
+            .telegram_errors
+            .with_label_values(&[&meter_id, "object"])
+            .inc_by(object_errors.into());
+        link_quality.record(&meter_id, LinkEvent::Telegram { object_errors });
+        span.record(
+            "meter_time",
+            tracing::field::display(data.electricity_data.time),
+        );
+        tracing::debug!("Parsed telegram");
 
-        if let Err(e) = data_tx.try_send(data) {
-            println!("Could not send data to database handler: {e:?}");
+        drop(_enter);
+        if let Err(e) = data_tx.blocking_send((index, data, span)) {
+            if let Some(suppressed) = send_errors.check() {
+                tracing::error!(suppressed, "Could not send data to database handler: {e:?}");
+            }
+            metrics::METRICS
+                .dropped_telegrams
+                .with_label_values(&[&meter_id])
+                .inc();
         }
     }
 }
 
-fn telegram_to_data(
-    telegram: Telegram,
-) -> Result<(ElectricityData, [Option<SlaveData>; 4]), Box<dyn Error>> {
-    let mut electricity_data = ElectricityData {
-        time: chrono::Utc::now(),
-        ..Default::default()
-    };
-    let mut slave_data = [None; 4];
-
-    for obj in telegram.objects() {
-        match obj {
-            Ok(obj) => match obj {
-                dsmr5::OBIS::MeterReadingTo(Tariff::Tariff1, ref val) => {
-                    electricity_data.kwh_import_total_tarif_low = f64::from(val) as f32;
-                }
-                dsmr5::OBIS::MeterReadingTo(Tariff::Tariff2, ref val) => {
-                    electricity_data.kwh_import_total_tarif_high = f64::from(val) as f32;
-                }
-                dsmr5::OBIS::MeterReadingBy(Tariff::Tariff1, ref val) => {
-                    electricity_data.kwh_export_total_tarif_low = f64::from(val) as f32;
-                }
-                dsmr5::OBIS::MeterReadingBy(Tariff::Tariff2, ref val) => {
-                    electricity_data.kwh_export_total_tarif_high = f64::from(val) as f32;
-                }
-                dsmr5::OBIS::InstantaneousVoltage(line, ref val) => {
-                    electricity_data.voltages[line as usize] = f64::from(val) as f32;
-                }
-                dsmr5::OBIS::InstantaneousCurrent(line, ref val) => {
-                    electricity_data.current[line as usize] = val.0 as f32;
-                }
-                dsmr5::OBIS::InstantaneousActivePowerPlus(line, ref val) => {
-                    electricity_data.active_powers_import[line as usize] = f64::from(val) as f32;
-                }
-                dsmr5::OBIS::InstantaneousActivePowerNeg(line, ref val) => {
-                    electricity_data.active_powers_export[line as usize] = f64::from(val) as f32;
-                }
-                dsmr5::OBIS::SlaveMeterReading(s, timestamp, Some(ref val)) => {
-                    let offset =
-                        chrono::FixedOffset::east_opt(if timestamp.dst { 2 } else { 1 } * 3600)
-                            .ok_or_else(|| format!("Timezone error!: {timestamp:?}"))?;
-
-                    slave_data[s as usize] = Some(SlaveData {
-                        time: offset
-                            .with_ymd_and_hms(
-                                timestamp.year as i32 + 2000,
-                                timestamp.month as u32,
-                                timestamp.day as u32,
-                                timestamp.hour as u32,
-                                timestamp.minute as u32,
-                                timestamp.second as u32,
-                            )
-                            .latest()
-                            .ok_or_else(|| format!("Time error!: {timestamp:?}"))?
-                            .to_utc(),
-                        value: f64::from(val) as f32,
-                    });
-                }
-                _ => {}
-            },
-            Err(e) => println!("Obj error: {e:?}"),
-        }
+fn init_logging(config: &settings::LogConfig) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::new(&config.level));
+    if config.json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
     }
-
-    Ok((electricity_data, slave_data))
-}
-
-#[derive(Debug, Default)]
-struct ElectricityData {
-    time: chrono::DateTime<Utc>,
-
-    kwh_import_total_tarif_low: f32,
-    kwh_import_total_tarif_high: f32,
-    kwh_export_total_tarif_low: f32,
-    kwh_export_total_tarif_high: f32,
-
-    voltages: [f32; 3],
-    current: [f32; 3],
-    active_powers_import: [f32; 3],
-    active_powers_export: [f32; 3],
-}
-
-#[derive(Debug, Copy, Clone, Default)]
-struct SlaveData {
-    time: chrono::DateTime<Utc>,
-    value: f32,
 }
@@ -0,0 +1,149 @@
+use std::sync::LazyLock;
+
+use metrics_registry::MetricsRegistry;
+use p1::ElectricityData;
+use prometheus::{GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts};
+
+const PHASES: [&str; 3] = ["l1", "l2", "l3"];
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Everything about a meter is labeled with its meter ID
+pub struct Metrics {
+    registry: MetricsRegistry,
+
+    /// W, import minus export
+    power: GaugeVec,
+    voltage: GaugeVec,
+    current: GaugeVec,
+    /// kWh, labeled with direction and tariff
+    energy: GaugeVec,
+    clock_drift: GaugeVec,
+
+    pub telegrams_parsed: IntCounterVec,
+    /// Labeled with the stage the telegram was rejected in, or `object` for objects skipped in accepted telegrams
+    pub telegram_errors: IntCounterVec,
+    pub dropped_telegrams: IntCounterVec,
+    pub spool_errors: IntCounter,
+    pub database_write_duration: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = MetricsRegistry::new("p1");
+
+        let metrics = Self {
+            power: GaugeVec::new(
+                Opts::new("power_watts", "Active power per phase, import minus export"),
+                &["meter", "phase"],
+            )
+            .unwrap(),
+            voltage: GaugeVec::new(
+                Opts::new("voltage_volts", "Voltage per phase"),
+                &["meter", "phase"],
+            )
+            .unwrap(),
+            current: GaugeVec::new(
+                Opts::new("current_amperes", "Current per phase"),
+                &["meter", "phase"],
+            )
+            .unwrap(),
+            energy: GaugeVec::new(
+                Opts::new("energy_kwh", "Energy counters of the meter"),
+                &["meter", "direction", "tariff"],
+            )
+            .unwrap(),
+            clock_drift: GaugeVec::new(
+                Opts::new(
+                    "clock_drift_seconds",
+                    "Time we received the last telegram minus the time the meter put in it",
+                ),
+                &["meter"],
+            )
+            .unwrap(),
+            telegrams_parsed: IntCounterVec::new(
+                Opts::new("telegrams_parsed_total", "Telegrams parsed"),
+                &["meter"],
+            )
+            .unwrap(),
+            telegram_errors: IntCounterVec::new(
+                Opts::new(
+                    "telegram_errors_total",
+                    "Telegrams that could not be read or parsed, and unparsable objects in telegrams",
+                ),
+                &["meter", "kind"],
+            )
+            .unwrap(),
+            dropped_telegrams: IntCounterVec::new(
+                Opts::new(
+                    "dropped_telegrams_total",
+                    "Parsed telegrams that could not be handed to the rest of the reader",
+                ),
+                &["meter"],
+            )
+            .unwrap(),
+            spool_errors: IntCounter::new(
+                "spool_errors_total",
+                "Telegrams that could not be spooled for the database, and failed syncs of the spool to disk",
+            )
+            .unwrap(),
+            database_write_duration: Histogram::with_opts(HistogramOpts::new(
+                "database_write_duration_seconds",
+                "Time it takes to write a batch of telegrams to the database",
+            ))
+            .unwrap(),
+            registry,
+        };
+
+        for collector in [
+            Box::new(metrics.power.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.voltage.clone()),
+            Box::new(metrics.current.clone()),
+            Box::new(metrics.energy.clone()),
+            Box::new(metrics.clock_drift.clone()),
+            Box::new(metrics.telegrams_parsed.clone()),
+            Box::new(metrics.telegram_errors.clone()),
+            Box::new(metrics.dropped_telegrams.clone()),
+            Box::new(metrics.spool_errors.clone()),
+            Box::new(metrics.database_write_duration.clone()),
+        ] {
+            metrics.registry.register(collector);
+        }
+
+        metrics
+    }
+
+    pub fn update(&self, meter_id: &str, data: &ElectricityData) {
+        for (i, phase) in PHASES.iter().enumerate() {
+            self.power.with_label_values(&[meter_id, phase]).set(
+                ((data.active_powers_import[i] - data.active_powers_export[i]) * 1000.0) as f64,
+            );
+            self.voltage
+                .with_label_values(&[meter_id, phase])
+                .set(data.voltages[i] as f64);
+            self.current
+                .with_label_values(&[meter_id, phase])
+                .set(data.current[i] as f64);
+        }
+
+        for (direction, tariff, value) in [
+            ("import", "low", data.kwh_import_total_tarif_low),
+            ("import", "high", data.kwh_import_total_tarif_high),
+            ("export", "low", data.kwh_export_total_tarif_low),
+            ("export", "high", data.kwh_export_total_tarif_high),
+        ] {
+            self.energy
+                .with_label_values(&[meter_id, direction, tariff])
+                .set(value as f64);
+        }
+
+        self.clock_drift
+            .with_label_values(&[meter_id])
+            .set((data.received_time - data.time).as_seconds_f64());
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        self.registry.encode()
+    }
+}
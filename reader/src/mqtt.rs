@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+
+use mqtt_publisher::{
+    discovery::{Device, Quantity, SensorConfig},
+    MqttPublisher,
+};
+use p1::{SlaveDevice, TelegramData};
+use serde_json::json;
+
+/// Publishes every telegram as json to `<topic_prefix>/electricity` and `<topic_prefix>/slave/<channel>`,
+/// along with the Home Assistant discovery configs for them.
+///
+/// When the reader reads more than one meter, the topics are `<topic_prefix>/<meter_id>/...` instead.
+pub struct TelegramPublisher {
+    publisher: MqttPublisher,
+    per_meter_topics: bool,
+    /// Per meter ID, the meters its discovery configs were made for and the configs
+    discovery: BTreeMap<String, ((String, [Option<SlaveDevice>; 4]), Vec<SensorConfig>)>,
+}
+
+impl TelegramPublisher {
+    pub fn new(publisher: MqttPublisher, per_meter_topics: bool) -> Self {
+        Self {
+            publisher,
+            per_meter_topics,
+            discovery: BTreeMap::new(),
+        }
+    }
+
+    pub fn publish(&mut self, meter_id: &str, data: &TelegramData) {
+        let meters = (
+            data.status.equipment_identifier.clone(),
+            data.slave_devices.clone(),
+        );
+        if self
+            .discovery
+            .get(meter_id)
+            .map(|(discovered, _)| discovered)
+            != Some(&meters)
+        {
+            let configs = self.discovery_configs(meter_id, data);
+            self.discovery.insert(meter_id.into(), (meters, configs));
+
+            // The publisher replaces all configs, so the other meters' are sent along
+            let configs = self
+                .discovery
+                .values()
+                .flat_map(|(_, configs)| configs.iter().cloned())
+                .collect::<Vec<_>>();
+            self.publisher.set_discovery(&configs);
+        }
+
+        for (topic, payload) in self.messages(meter_id, data) {
+            self.publisher.publish_json(&topic, &payload);
+        }
+    }
+
+    /// The topics under the prefix and the payloads a telegram is published as
+    fn messages(&self, meter_id: &str, data: &TelegramData) -> Vec<(String, serde_json::Value)> {
+        let mut messages = vec![(
+            self.meter_topic(meter_id, "electricity"),
+            json!(data.electricity_data),
+        )];
+
+        for slave_reading in data.slave_readings() {
+            messages.push((
+                self.meter_topic(meter_id, &format!("slave/{}", slave_reading.channel)),
+                json!(slave_reading),
+            ));
+        }
+
+        messages
+    }
+
+    /// The topic under the prefix
+    fn meter_topic(&self, meter_id: &str, topic: &str) -> String {
+        if self.per_meter_topics {
+            format!("{meter_id}/{topic}")
+        } else {
+            topic.into()
+        }
+    }
+
+    fn discovery_configs(&self, meter_id: &str, data: &TelegramData) -> Vec<SensorConfig> {
+        let (fallback_identifier, name) = if self.per_meter_topics {
+            (
+                format!("p1-meter-{meter_id}"),
+                format!("P1 meter {meter_id}"),
+            )
+        } else {
+            ("p1-meter".into(), "P1 meter".into())
+        };
+        let meter = Device {
+            identifiers: vec![if data.status.equipment_identifier.is_empty() {
+                fallback_identifier
+            } else {
+                data.status.equipment_identifier.clone()
+            }],
+            name,
+            manufacturer: None,
+            model: Some("DSMR 5".into()),
+        };
+        let electricity_topic = self
+            .publisher
+            .topic(&self.meter_topic(meter_id, "electricity"));
+
+        let mut configs = vec![SensorConfig::new(
+            &meter,
+            "power",
+            "Power",
+            Quantity::PowerKilowatt,
+            electricity_topic.clone(),
+            "{{ (value_json.active_powers_import | sum - value_json.active_powers_export | sum) | round(3) }}",
+        )];
+
+        for (object_id, name, field) in [
+            (
+                "energy_import_low",
+                "Energy import low tariff",
+                "kwh_import_total_tarif_low",
+            ),
+            (
+                "energy_import_high",
+                "Energy import high tariff",
+                "kwh_import_total_tarif_high",
+            ),
+            (
+                "energy_export_low",
+                "Energy export low tariff",
+                "kwh_export_total_tarif_low",
+            ),
+            (
+                "energy_export_high",
+                "Energy export high tariff",
+                "kwh_export_total_tarif_high",
+            ),
+        ] {
+            configs.push(SensorConfig::new(
+                &meter,
+                object_id,
+                name,
+                Quantity::EnergyKilowattHour,
+                electricity_topic.clone(),
+                &format!("{{{{ value_json.{field} }}}}"),
+            ));
+        }
+
+        for line in 0..3 {
+            let phase = line + 1;
+            configs.push(SensorConfig::new(
+                &meter,
+                &format!("voltage_l{phase}"),
+                &format!("Voltage L{phase}"),
+                Quantity::Voltage,
+                electricity_topic.clone(),
+                &format!("{{{{ value_json.voltages[{line}] }}}}"),
+            ));
+            configs.push(SensorConfig::new(
+                &meter,
+                &format!("current_l{phase}"),
+                &format!("Current L{phase}"),
+                Quantity::Current,
+                electricity_topic.clone(),
+                &format!("{{{{ value_json.current[{line}] }}}}"),
+            ));
+            configs.push(SensorConfig::new(
+                &meter,
+                &format!("power_l{phase}"),
+                &format!("Power L{phase}"),
+                Quantity::PowerKilowatt,
+                electricity_topic.clone(),
+                &format!("{{{{ (value_json.active_powers_import[{line}] - value_json.active_powers_export[{line}]) | round(3) }}}}"),
+            ));
+        }
+
+        for (i, slave_device) in data.slave_devices.iter().enumerate() {
+            let Some(slave_device) = slave_device else {
+                continue;
+            };
+
+            let device = Device {
+                identifiers: vec![slave_device.equipment_identifier.clone()],
+                name: format!("{} meter", slave_device.name()),
+                manufacturer: None,
+                model: None,
+            };
+            let quantity = match slave_device.device_type {
+                0x02 => Quantity::EnergyKilowattHour,
+                0x03 => Quantity::Gas,
+                0x06 | 0x07 | 0x16 => Quantity::Water,
+                0x04 | 0x0A | 0x0B | 0x0C | 0x0D => Quantity::EnergyGigajoule,
+                _ => Quantity::Total(slave_device.unit().into()),
+            };
+
+            configs.push(SensorConfig::new(
+                &device,
+                "value",
+                slave_device.name(),
+                quantity,
+                self.publisher
+                    .topic(&self.meter_topic(meter_id, &format!("slave/{i}"))),
+                "{{ value_json.value }}",
+            ));
+        }
+
+        configs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mqtt_publisher::MqttConfig;
+    use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+
+    use super::*;
+    use p1::{ElectricityData, MeterStatus, SlaveData};
+
+    fn telegram() -> TelegramData {
+        TelegramData {
+            electricity_data: ElectricityData {
+                kwh_import_total_tarif_low: 123.5,
+                ..Default::default()
+            },
+            slave_data: [
+                None,
+                Some(SlaveData {
+                    time: Default::default(),
+                    value: 4.25,
+                }),
+                None,
+                None,
+            ],
+            slave_devices: [
+                None,
+                Some(SlaveDevice {
+                    device_type: 0x03,
+                    equipment_identifier: "G0012".into(),
+                }),
+                None,
+                None,
+            ],
+            status: MeterStatus {
+                equipment_identifier: "E0043007052870318".into(),
+                ..Default::default()
+            },
+            power_failure_events: Vec::new(),
+        }
+    }
+
+    /// Never connects, nothing listens on port 1
+    fn publisher(per_meter_topics: bool) -> TelegramPublisher {
+        TelegramPublisher::new(
+            MqttPublisher::start(MqttConfig {
+                host: "localhost".into(),
+                port: 1,
+                client_id: "p1-reader-test".into(),
+                credentials: None,
+                topic_prefix: "p1".into(),
+                qos: QoS::AtMostOnce,
+                retain: false,
+            }),
+            per_meter_topics,
+        )
+    }
+
+    #[tokio::test]
+    async fn messages_and_discovery() {
+        let data = telegram();
+
+        let messages = publisher(false).messages("main", &data);
+        let topics: Vec<_> = messages.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(topics, ["electricity", "slave/1"]);
+        assert_eq!(messages[0].1["kwh_import_total_tarif_low"], 123.5);
+        assert_eq!(messages[1].1["value"], 4.25);
+        assert_eq!(messages[1].1["name"], "Gas");
+
+        let publisher = publisher(true);
+        let messages = publisher.messages("garage", &data);
+        let topics: Vec<_> = messages.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(topics, ["garage/electricity", "garage/slave/1"]);
+
+        let configs = serde_json::to_value(publisher.discovery_configs("garage", &data)).unwrap();
+        let configs = configs.as_array().unwrap();
+        assert_eq!(configs[0]["state_topic"], "p1/garage/electricity");
+        assert_eq!(
+            configs[0]["device"]["identifiers"],
+            json!(["E0043007052870318"])
+        );
+        let gas = configs.last().unwrap();
+        assert_eq!(gas["state_topic"], "p1/garage/slave/1");
+        assert_eq!(gas["device"]["identifiers"], json!(["G0012"]));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a local mosquitto instance on port 1883"]
+    async fn publish_to_mosquitto() {
+        let (subscriber, mut subscriber_loop) = AsyncClient::new(
+            MqttOptions::new("p1-reader-test-subscriber", "localhost", 1883),
+            10,
+        );
+        subscriber
+            .subscribe("p1-reader-test/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        // Wait until the subscription is active
+        loop {
+            if let Event::Incoming(Packet::SubAck(_)) = subscriber_loop.poll().await.unwrap() {
+                break;
+            }
+        }
+
+        let mut publisher = TelegramPublisher::new(
+            MqttPublisher::start(MqttConfig {
+                host: "localhost".into(),
+                port: 1883,
+                client_id: "p1-reader-test-publisher".into(),
+                credentials: None,
+                topic_prefix: "p1-reader-test".into(),
+                qos: QoS::AtLeastOnce,
+                retain: false,
+            }),
+            false,
+        );
+        publisher.publish(
+            settings::DEFAULT_METER_ID,
+            &TelegramData {
+                electricity_data: ElectricityData {
+                    kwh_import_total_tarif_low: 123.5,
+                    ..Default::default()
+                },
+                slave_data: [None; 4],
+                slave_devices: Default::default(),
+                status: MeterStatus::default(),
+                power_failure_events: Vec::new(),
+            },
+        );
+
+        let publish = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Event::Incoming(Packet::Publish(publish)) =
+                    subscriber_loop.poll().await.unwrap()
+                {
+                    break publish;
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(publish.topic, "p1-reader-test/electricity");
+        let payload: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(payload["kwh_import_total_tarif_low"], 123.5);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use sqlx::postgres::PgPool;
+
+/// Something that is collected in memory and added to what's already in the database
+pub trait Merge: Default {
+    fn merge(&mut self, other: Self);
+}
+
+/// Values collected per key until they're written to the database
+#[derive(Debug)]
+pub struct Pending<K, V> {
+    values: Mutex<BTreeMap<K, V>>,
+}
+
+impl<K, V> Default for Pending<K, V> {
+    fn default() -> Self {
+        Self {
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl<K: Ord, V: Merge> Pending<K, V> {
+    pub fn add(&self, key: K, value: V) {
+        self.values
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .merge(value);
+    }
+}
+
+/// Writes what's pending with `write` every minute, `what` is what's written for the logs.
+///
+/// `write` has to merge the values with what's already in the database, so restarts don't lose anything. When it
+/// fails, the values are merged back in to be written along with whatever was collected in the meantime.
+pub async fn run_pending_writer<K: Ord, V: Merge>(
+    pool: PgPool,
+    pending: Arc<Pending<K, V>>,
+    what: &str,
+    write: impl AsyncFn(&PgPool, &BTreeMap<K, V>) -> Result<(), sqlx::Error>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut failing = false;
+
+    loop {
+        interval.tick().await;
+
+        let values = mem::take(&mut *pending.values.lock().unwrap());
+        if values.is_empty() {
+            continue;
+        }
+
+        match write(&pool, &values).await {
+            Ok(()) => failing = false,
+            Err(e) => {
+                if !failing {
+                    failing = true;
+                    tracing::error!("Could not write {what}: {e}");
+                }
+
+                for (key, value) in values {
+                    pending.add(key, value);
+                }
+            }
+        }
+    }
+}
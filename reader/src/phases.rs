@@ -0,0 +1,290 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use p1::ElectricityData;
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+
+use crate::pending::{run_pending_writer, Merge, Pending};
+
+/// Below this average phase current the imbalance is mostly noise
+const MIN_IMBALANCE_CURRENT: f32 = 1.0;
+const PHASES: [&str; 3] = ["L1", "L2", "L3"];
+
+/// The phase currents in A, negative on phases that export.
+///
+/// Meters only report the size of the current, the direction comes from the phase's power.
+pub fn signed_currents(data: &ElectricityData) -> [f32; 3] {
+    std::array::from_fn(|i| {
+        if data.active_powers_export[i] > data.active_powers_import[i] {
+            -data.current[i]
+        } else {
+            data.current[i]
+        }
+    })
+}
+
+/// The current in the neutral conductor, assuming the phase currents are 120° apart and in phase with their
+/// voltages. An exporting phase's current is 180° turned, which the sign takes care of.
+pub fn neutral_current(currents: [f32; 3]) -> f32 {
+    let [l1, l2, l3] = currents;
+    (l1 * l1 + l2 * l2 + l3 * l3 - l1 * l2 - l2 * l3 - l3 * l1)
+        .max(0.0)
+        .sqrt()
+}
+
+/// %, the largest deviation of a phase current from the average of the three, `None` when there's too little
+/// current to tell
+pub fn imbalance(currents: [f32; 3]) -> Option<f32> {
+    let currents = currents.map(f32::abs);
+    let average = currents.iter().sum::<f32>() / 3.0;
+    if average < MIN_IMBALANCE_CURRENT {
+        return None;
+    }
+
+    let max_deviation = currents
+        .iter()
+        .map(|current| (current - average).abs())
+        .fold(0.0, f32::max);
+    Some(max_deviation / average * 100.0)
+}
+
+/// Sums of one day, see the `phase_stats_daily` table
+#[derive(Debug, Default, Clone, Copy)]
+struct DayStats {
+    samples: u32,
+    current_sum: [f64; 3],
+    current_max: [f32; 3],
+    net_power_sum: [f64; 3],
+    neutral_current_sum: f64,
+    neutral_current_max: f32,
+    imbalance_samples: u32,
+    imbalance_sum: f64,
+    imbalance_max: Option<f32>,
+}
+
+impl Merge for DayStats {
+    fn merge(&mut self, other: DayStats) {
+        self.samples += other.samples;
+        for i in 0..3 {
+            self.current_sum[i] += other.current_sum[i];
+            self.current_max[i] = self.current_max[i].max(other.current_max[i]);
+            self.net_power_sum[i] += other.net_power_sum[i];
+        }
+        self.neutral_current_sum += other.neutral_current_sum;
+        self.neutral_current_max = self.neutral_current_max.max(other.neutral_current_max);
+        self.imbalance_samples += other.imbalance_samples;
+        self.imbalance_sum += other.imbalance_sum;
+        self.imbalance_max = self
+            .imbalance_max
+            .into_iter()
+            .chain(other.imbalance_max)
+            .reduce(f32::max);
+    }
+}
+
+/// Collects per-phase statistics per meter and day until they're written to the database
+#[derive(Debug, Default)]
+pub struct PhaseStats {
+    days: Arc<Pending<(String, NaiveDate), DayStats>>,
+    /// Meters that reported anything on L2 or L3 since the reader started
+    three_phase: Mutex<HashSet<String>>,
+}
+
+impl PhaseStats {
+    pub fn record(&self, meter_id: &str, timezone: Tz, data: &ElectricityData) {
+        let currents = signed_currents(data);
+
+        let three_phase = {
+            let mut three_phase = self.three_phase.lock().unwrap();
+            if !three_phase.contains(meter_id)
+                && (1..3).any(|i| {
+                    data.voltages[i] > 0.0
+                        || data.current[i] > 0.0
+                        || data.active_powers_import[i] > 0.0
+                        || data.active_powers_export[i] > 0.0
+                })
+            {
+                three_phase.insert(meter_id.into());
+            }
+            three_phase.contains(meter_id)
+        };
+
+        let mut sample = DayStats {
+            samples: 1,
+            current_max: data.current,
+            neutral_current_max: neutral_current(currents),
+            ..Default::default()
+        };
+        for i in 0..3 {
+            sample.current_sum[i] = data.current[i].into();
+            sample.net_power_sum[i] =
+                (data.active_powers_import[i] - data.active_powers_export[i]).into();
+        }
+        sample.neutral_current_sum = sample.neutral_current_max.into();
+        if let Some(imbalance) = imbalance(currents).filter(|_| three_phase) {
+            sample.imbalance_samples = 1;
+            sample.imbalance_sum = imbalance.into();
+            sample.imbalance_max = Some(imbalance);
+        }
+
+        let day = data.time.with_timezone(&timezone).date_naive();
+        self.days.add((meter_id.into(), day), sample);
+    }
+}
+
+/// Merges the statistics into the `phase_stats_daily` table every minute, weighing averages by their samples
+pub async fn run_phase_stats_writer(pool: PgPool, phase_stats: Arc<PhaseStats>) {
+    run_pending_writer(
+        pool,
+        phase_stats.days.clone(),
+        "phase statistics",
+        write_stats,
+    )
+    .await;
+}
+
+async fn write_stats(
+    pool: &PgPool,
+    days: &BTreeMap<(String, NaiveDate), DayStats>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    for ((meter_id, day), stats) in days {
+        let samples = f64::from(stats.samples);
+        let average = |sums: [f64; 3]| sums.map(|sum| (sum / samples) as f32).to_vec();
+        let imbalance_avg = (stats.imbalance_samples > 0)
+            .then(|| (stats.imbalance_sum / f64::from(stats.imbalance_samples)) as f32);
+
+        // Averages are merged weighted by their samples
+        sqlx::query!(
+            r#"INSERT INTO phase_stats_daily AS t (
+                meter_id, day, samples, current_avg, current_max, net_power_avg,
+                neutral_current_avg, neutral_current_max, imbalance_samples, imbalance_avg, imbalance_max
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (meter_id, day) DO UPDATE SET
+                samples = t.samples + EXCLUDED.samples,
+                current_avg = ARRAY(
+                    SELECT (old * t.samples + new * EXCLUDED.samples) / (t.samples + EXCLUDED.samples)
+                    FROM unnest(t.current_avg, EXCLUDED.current_avg) WITH ORDINALITY AS u(old, new, i)
+                    ORDER BY i
+                ),
+                current_max = ARRAY(
+                    SELECT greatest(old, new)
+                    FROM unnest(t.current_max, EXCLUDED.current_max) WITH ORDINALITY AS u(old, new, i)
+                    ORDER BY i
+                ),
+                net_power_avg = ARRAY(
+                    SELECT (old * t.samples + new * EXCLUDED.samples) / (t.samples + EXCLUDED.samples)
+                    FROM unnest(t.net_power_avg, EXCLUDED.net_power_avg) WITH ORDINALITY AS u(old, new, i)
+                    ORDER BY i
+                ),
+                neutral_current_avg = (t.neutral_current_avg * t.samples + EXCLUDED.neutral_current_avg * EXCLUDED.samples)
+                    / (t.samples + EXCLUDED.samples),
+                neutral_current_max = greatest(t.neutral_current_max, EXCLUDED.neutral_current_max),
+                imbalance_samples = t.imbalance_samples + EXCLUDED.imbalance_samples,
+                imbalance_avg = (coalesce(t.imbalance_avg * t.imbalance_samples, 0) + coalesce(EXCLUDED.imbalance_avg * EXCLUDED.imbalance_samples, 0))
+                    / nullif(t.imbalance_samples + EXCLUDED.imbalance_samples, 0),
+                imbalance_max = greatest(t.imbalance_max, EXCLUDED.imbalance_max)"#,
+            meter_id,
+            day,
+            stats.samples as i32,
+            &average(stats.current_sum),
+            &stats.current_max,
+            &average(stats.net_power_sum),
+            (stats.neutral_current_sum / samples) as f32,
+            stats.neutral_current_max,
+            stats.imbalance_samples as i32,
+            imbalance_avg,
+            stats.imbalance_max,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+/// A row of `phase_stats_daily`
+#[derive(Debug, Serialize)]
+pub struct PhaseDay {
+    pub day: NaiveDate,
+    pub samples: i32,
+    pub current_avg: Vec<f32>,
+    pub current_max: Vec<f32>,
+    pub net_power_avg: Vec<f32>,
+    pub neutral_current_avg: f32,
+    pub neutral_current_max: f32,
+    pub imbalance_avg: Option<f32>,
+    pub imbalance_max: Option<f32>,
+}
+
+/// Which phase to put what on, going by the average net power of every phase
+#[derive(Debug, Serialize)]
+pub struct PhaseAdvice {
+    /// kW per phase, import minus export, over all days
+    pub net_power_avg: [f32; 3],
+    /// The least loaded phase. With a single-phase PV inverter that's usually the inverter's phase, where
+    /// new loads use the solar power directly.
+    pub new_load_phase: &'static str,
+    /// The most loaded phase, where a single-phase inverter offsets the most load and evens out the phases
+    pub pv_phase: &'static str,
+    pub explanation: String,
+}
+
+/// `None` without data, or for single phase meters
+pub fn advise(days: &[PhaseDay]) -> Option<PhaseAdvice> {
+    let three_phase = days
+        .iter()
+        .any(|day| day.current_max.iter().skip(1).any(|current| *current > 0.0));
+    let samples: f64 = days.iter().map(|day| f64::from(day.samples)).sum();
+    if !three_phase || samples == 0.0 {
+        return None;
+    }
+
+    let net_power_avg: [f32; 3] = std::array::from_fn(|i| {
+        let sum: f64 = days
+            .iter()
+            .map(|day| f64::from(day.net_power_avg[i]) * f64::from(day.samples))
+            .sum();
+        (sum / samples) as f32
+    });
+
+    let by_load = |a: &usize, b: &usize| net_power_avg[*a].total_cmp(&net_power_avg[*b]);
+    let least = (0..3).min_by(by_load).unwrap();
+    let most = (0..3).max_by(by_load).unwrap();
+
+    Some(PhaseAdvice {
+        net_power_avg,
+        new_load_phase: PHASES[least],
+        pv_phase: PHASES[most],
+        explanation: format!(
+            "{} has the least load at {:.2} kW on average, {} the most at {:.2} kW",
+            PHASES[least], net_power_avg[least], PHASES[most], net_power_avg[most]
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutral_current_and_imbalance() {
+        // Balanced phases cancel out in the neutral
+        assert!(neutral_current([10.0, 10.0, 10.0]) < 0.01);
+        // A single loaded phase returns all of its current through the neutral
+        assert!((neutral_current([10.0, 0.0, 0.0]) - 10.0).abs() < 0.01);
+        // A phase exporting what another imports adds up in the neutral
+        assert!((neutral_current([10.0, -10.0, 0.0]) - 17.32).abs() < 0.01);
+
+        assert_eq!(imbalance([10.0, 10.0, 10.0]), Some(0.0));
+        assert_eq!(imbalance([20.0, 5.0, 5.0]), Some(100.0));
+        assert_eq!(imbalance([0.5, 0.0, 0.0]), None);
+    }
+}
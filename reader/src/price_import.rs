@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use prices::Price;
+use sqlx::postgres::PgPool;
+
+/// Imports the day-ahead prices of new and changed files in `dir` into the `prices` table, checking every
+/// minute.
+///
+/// Files are ENTSO-E XML (`.xml`) or CSV (`.csv`), see the `prices` crate. Prices that are already there are
+/// replaced, so a file with quarter-hourly prices can be dropped next to an older one with hourly prices.
+pub async fn run_price_import(pool: PgPool, dir: PathBuf) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // When every file was last modified when it was imported, or found to be invalid
+    let mut imported: HashMap<PathBuf, SystemTime> = HashMap::new();
+    let mut failing = false;
+
+    loop {
+        interval.tick().await;
+
+        let files = match price_files(&dir) {
+            Ok(files) => files,
+            Err(e) => {
+                if !failing {
+                    failing = true;
+                    tracing::error!("Could not list price files in {}: {e}", dir.display());
+                }
+                continue;
+            }
+        };
+
+        for (path, modified) in files {
+            if imported.get(&path) == Some(&modified) {
+                continue;
+            }
+
+            let prices = match prices::read_file(&path) {
+                Ok(prices) => prices,
+                Err(e) => {
+                    // Not tried again until the file changes
+                    tracing::warn!("Skipping price file {}: {e}", path.display());
+                    imported.insert(path, modified);
+                    continue;
+                }
+            };
+
+            let source = path.file_name().unwrap_or_default().to_string_lossy();
+            match write_prices(&pool, &prices, &source).await {
+                Ok(()) => {
+                    failing = false;
+                    tracing::info!(
+                        "Imported {} prices from {}, {} to {}",
+                        prices.len(),
+                        path.display(),
+                        prices[0].time,
+                        prices[prices.len() - 1].end()
+                    );
+                    imported.insert(path, modified);
+                }
+                Err(e) => {
+                    if !failing {
+                        failing = true;
+                        tracing::error!("Could not write prices: {e}");
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// The `.xml` and `.csv` files in `dir`, with when they were last modified
+fn price_files(dir: &Path) -> std::io::Result<Vec<(PathBuf, SystemTime)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_price_file = path
+            .extension()
+            .is_some_and(|extension| extension == "xml" || extension == "csv");
+        let metadata = entry.metadata()?;
+        if is_price_file && metadata.is_file() {
+            files.push((path, metadata.modified()?));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+async fn write_prices(pool: &PgPool, prices: &[Price], source: &str) -> Result<(), sqlx::Error> {
+    let times: Vec<_> = prices.iter().map(|price| price.time).collect();
+    let durations: Vec<_> = prices
+        .iter()
+        .map(|price| price.duration.num_seconds() as i32)
+        .collect();
+    let amounts: Vec<_> = prices.iter().map(|price| price.price).collect();
+
+    sqlx::query!(
+        "INSERT INTO prices (time, duration, price, source)
+        SELECT time, duration, price, $4 FROM unnest($1::TIMESTAMPTZ[], $2::INTEGER[], $3::REAL[]) AS p(time, duration, price)
+        ON CONFLICT (time) DO UPDATE SET
+            duration = EXCLUDED.duration,
+            price = EXCLUDED.price,
+            source = EXCLUDED.source",
+        &times,
+        &durations,
+        &amounts,
+        source,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use std::time::{Duration, Instant};
+
+/// Limits how often a repeated error is logged.
+///
+/// A broken cable or a noisy line makes every telegram fail the same way, which would otherwise flood the logs.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Returns the number of suppressed occurrences since the last one that was let through, or `None` when this
+    /// one should be suppressed as well
+    pub fn check(&mut self) -> Option<u64> {
+        let now = Instant::now();
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            self.suppressed += 1;
+            return None;
+        }
+
+        self.last = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use chrono::{TimeDelta, Utc};
+use sqlx::postgres::PgPool;
+
+/// Rows are deleted in chunks of this much time, so a large backlog doesn't end up in one huge transaction
+const DELETE_CHUNK: TimeDelta = TimeDelta::days(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionConfig {
+    /// Raw electricity data older than this is deleted
+    pub max_age: TimeDelta,
+    /// Only log what would be deleted
+    pub dry_run: bool,
+}
+
+/// Deletes old raw electricity data of every meter once a day.
+///
+/// Only rows that are already in the rollup tables are deleted, so nothing is lost when the rollups
+/// are behind.
+pub async fn run_retention(pool: PgPool, config: RetentionConfig, meter_ids: Vec<String>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        for meter_id in &meter_ids {
+            if let Err(e) = apply_retention(&pool, config, meter_id).await {
+                tracing::error!("Could not apply retention to meter {meter_id}: {e}");
+            }
+        }
+    }
+}
+
+pub async fn apply_retention(
+    pool: &PgPool,
+    config: RetentionConfig,
+    meter_id: &str,
+) -> Result<(), sqlx::Error> {
+    // The last minute rollup is still being filled, everything before it is safe
+    let Some(rolled_up_until) = sqlx::query_scalar!(
+        "SELECT max(time) FROM electricity_rollup_minute WHERE meter_id = $1",
+        meter_id
+    )
+    .fetch_one(pool)
+    .await?
+    else {
+        tracing::info!(
+            "Retention: nothing of meter {meter_id} has been rolled up yet, not deleting anything"
+        );
+        return Ok(());
+    };
+
+    let cutoff = (Utc::now() - config.max_age).min(rolled_up_until);
+
+    if config.dry_run {
+        let count = sqlx::query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM electricity_data_points WHERE meter_id = $1 AND time < $2"#,
+            meter_id,
+            cutoff
+        )
+        .fetch_one(pool)
+        .await?;
+        tracing::info!(
+            "Retention dry run: would delete {count} electricity data points of meter {meter_id} before {cutoff}"
+        );
+        return Ok(());
+    }
+
+    let Some(mut start) = sqlx::query_scalar!(
+        "SELECT min(time) FROM electricity_data_points WHERE meter_id = $1",
+        meter_id
+    )
+    .fetch_one(pool)
+    .await?
+    else {
+        return Ok(());
+    };
+
+    let mut deleted = 0;
+    while start < cutoff {
+        let end = (start + DELETE_CHUNK).min(cutoff);
+        deleted += sqlx::query!(
+            "DELETE FROM electricity_data_points WHERE meter_id = $1 AND time < $2",
+            meter_id,
+            end
+        )
+        .execute(pool)
+        .await?
+        .rows_affected();
+        start = end;
+    }
+
+    if deleted > 0 {
+        tracing::info!(
+            "Retention: deleted {deleted} electricity data points of meter {meter_id} before {cutoff}"
+        );
+    }
+
+    Ok(())
+}
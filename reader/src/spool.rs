@@ -0,0 +1,154 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A write-ahead queue on disk.
+///
+/// Entries are appended as json lines to `spool.jsonl`.
+/// How far the entries have been processed is kept in `spool.offset`,
+/// so nothing is lost or processed twice across restarts.
+/// Once everything has been processed, the files are emptied again.
+///
+/// Pushing doesn't sync to disk, that's done for a whole group of entries with the handle from [Self::unsynced].
+pub struct Spool<T> {
+    data_path: PathBuf,
+    offset_path: PathBuf,
+    data_file: File,
+    /// Everything before this offset has been processed
+    offset: u64,
+    /// Whether entries were pushed since the last [Self::unsynced]
+    unsynced: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Spool<T> {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let data_path = dir.join("spool.jsonl");
+        let offset_path = dir.join("spool.offset");
+
+        let data_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&data_path)?;
+
+        let offset = match fs::read_to_string(&offset_path) {
+            Ok(offset) => offset
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        // A crash during a write can leave a partial line at the end. Cut it off.
+        let mut reader = BufReader::new(File::open(&data_path)?);
+        let mut complete_len = 0;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            complete_len += read as u64;
+        }
+        if complete_len != data_file.metadata()?.len() {
+            data_file.set_len(complete_len)?;
+        }
+
+        Ok(Self {
+            data_path,
+            offset_path,
+            data_file,
+            offset: offset.min(complete_len),
+            unsynced: false,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Append an entry, it's only on disk once the handle from [Self::unsynced] is synced
+    pub fn push(&mut self, entry: &T) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let len = self.data_file.metadata()?.len();
+        if let Err(e) = self.data_file.write_all(&line) {
+            // Don't leave a partial line in front of the next entry
+            let _ = self.data_file.set_len(len);
+            return Err(e);
+        }
+        self.unsynced = true;
+        Ok(())
+    }
+
+    /// A handle to sync the entries pushed since the last call to disk with, so that slow syncs don't hold up
+    /// whoever is waiting for the spool. `None` when nothing was pushed.
+    pub fn unsynced(&mut self) -> io::Result<Option<File>> {
+        if !self.unsynced {
+            return Ok(None);
+        }
+
+        let file = self.data_file.try_clone()?;
+        self.unsynced = false;
+        Ok(Some(file))
+    }
+
+    /// Read up to `max` unprocessed entries, paired with the offset to [Self::commit] once they're processed.
+    ///
+    /// Entries that can't be read are skipped and passed to `skipped`, so whatever was kept for them can be cleaned up.
+    pub fn peek(
+        &mut self,
+        max: usize,
+        mut skipped: impl FnMut(&[u8]),
+    ) -> io::Result<Vec<(T, u64)>> {
+        let mut reader = BufReader::new(File::open(&self.data_path)?);
+        reader.seek(SeekFrom::Start(self.offset))?;
+
+        let mut entries = Vec::new();
+        let mut offset = self.offset;
+        let mut line = Vec::new();
+
+        while entries.len() < max {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            offset += read as u64;
+
+            match serde_json::from_slice(&line) {
+                Ok(entry) => entries.push((entry, offset)),
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable spool entry: {e}");
+                    skipped(&line);
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Mark everything up to the offset as processed
+    pub fn commit(&mut self, offset: u64) -> io::Result<()> {
+        let len = self.data_file.metadata()?.len();
+
+        self.offset = if offset >= len {
+            self.data_file.set_len(0)?;
+            0
+        } else {
+            offset
+        };
+
+        let tmp_path = self.offset_path.with_extension("offset.tmp");
+        fs::write(&tmp_path, self.offset.to_string())?;
+        fs::rename(tmp_path, &self.offset_path)
+    }
+}
@@ -0,0 +1,154 @@
+use serde::Deserialize;
+
+use crate::{Error, File, TariffFile, env_override};
+
+/// `[battery_sim]`
+#[derive(Debug, Clone)]
+pub struct BatterySimConfig {
+    /// `[battery_sim.tariff]`, or a fixed tariff from `import_cost` and `export_cost`
+    pub tariff: prices::Tariff,
+    /// Every battery is simulated and compared to having no battery
+    pub batteries: Vec<BatteryConfig>,
+}
+
+/// `[[battery_sim.batteries]]`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatteryConfig {
+    /// kWh
+    pub capacity: f32,
+    /// kW
+    pub max_charging_rate: f32,
+    /// kW
+    pub max_discharging_rate: f32,
+    /// 0..1
+    pub efficiency: f32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BatterySimFile {
+    import_cost: Option<f32>,
+    export_cost: Option<f32>,
+    tariff: TariffFile,
+    batteries: Option<Vec<BatteryConfig>>,
+}
+
+impl BatterySimConfig {
+    pub fn load() -> Result<Self, Error> {
+        Self::resolve(File::load()?.battery_sim)
+    }
+
+    fn resolve(mut file: BatterySimFile) -> Result<Self, Error> {
+        env_override(
+            &mut file.import_cost,
+            "IMPORT_COST",
+            "battery_sim.import_cost",
+        )?;
+        env_override(
+            &mut file.export_cost,
+            "EXPORT_COST",
+            "battery_sim.export_cost",
+        )?;
+
+        let batteries = file.batteries.unwrap_or_else(default_batteries);
+        for (i, battery) in batteries.iter().enumerate() {
+            if !(battery.efficiency > 0.0 && battery.efficiency <= 1.0) {
+                return Err(Error::Invalid {
+                    key: format!("battery_sim.batteries[{i}].efficiency"),
+                    message: "must be more than 0 and at most 1".into(),
+                });
+            }
+        }
+
+        let tariff = match file.tariff.resolve("battery_sim")? {
+            Some(_) if file.import_cost.is_some() || file.export_cost.is_some() => {
+                return Err(Error::Invalid {
+                    key: "battery_sim.tariff".into(),
+                    message: "can't be combined with `import_cost` or `export_cost`".into(),
+                });
+            }
+            Some(tariff) => tariff,
+            None => {
+                let import_cost = file.import_cost.unwrap_or(0.25);
+                // Negative when exporting pays, where the tariff has what exporting pays
+                let export_price = -file.export_cost.unwrap_or(-0.01);
+                prices::Tariff::Fixed {
+                    import_high: import_cost,
+                    import_low: import_cost,
+                    export_high: export_price,
+                    export_low: export_price,
+                }
+            }
+        };
+
+        Ok(Self { tariff, batteries })
+    }
+}
+
+fn default_batteries() -> Vec<BatteryConfig> {
+    [
+        (0.1, 5.0),
+        (0.2, 5.0),
+        (0.5, 5.0),
+        (1.0, 5.0),
+        (2.0, 5.0),
+        (5.0, 5.0),
+        (10.0, 5.0),
+        (20.0, 10.0),
+        (50.0, 10.0),
+        (100.0, 10.0),
+        (200.0, 10.0),
+        (500.0, 10.0),
+        (1000.0, 10.0),
+        (2000.0, 10.0),
+        (5000.0, 10.0),
+    ]
+    .into_iter()
+    .map(|(capacity, rate)| BatteryConfig {
+        capacity,
+        max_charging_rate: rate,
+        max_discharging_rate: rate,
+        efficiency: 0.95,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{file, invalid_key, lock_env};
+
+    fn resolve(toml: &str) -> Result<BatterySimConfig, Error> {
+        BatterySimConfig::resolve(file(&format!("[battery_sim]\n{toml}")).battery_sim)
+    }
+
+    #[test]
+    fn tariff_or_flat_costs() {
+        let _env = lock_env();
+
+        assert_eq!(
+            resolve("import_cost = 0.3\nexport_cost = -0.05")
+                .unwrap()
+                .tariff,
+            prices::Tariff::Fixed {
+                import_high: 0.3,
+                import_low: 0.3,
+                export_high: 0.05,
+                export_low: 0.05,
+            }
+        );
+        assert_eq!(
+            invalid_key(resolve(
+                "import_cost = 0.3\n\n[battery_sim.tariff]\ntype = 'dynamic'"
+            )),
+            "battery_sim.tariff"
+        );
+        assert_eq!(
+            invalid_key(resolve(
+                "[[battery_sim.batteries]]\ncapacity = 5.0\nmax_charging_rate = 2.0\nmax_discharging_rate = 2.0\nefficiency = 0.0"
+            )),
+            "battery_sim.batteries[0].efficiency"
+        );
+    }
+}
@@ -0,0 +1,607 @@
+//! The config of all binaries, read from one TOML file.
+//!
+//! The file is read from `CONFIG_FILE`, or `config.toml` in the working directory when that exists.
+//! Every binary has its own section (`[reader]`, `[solar_reader]`, `[battery_sim]`, `[simulator]`), and
+//! most keys can be overridden with an env var, e.g. `DATABASE_URL` overrides `reader.database_url` in the
+//! reader.
+
+use std::{env, fmt::Display, fs, io, net::SocketAddr, path::PathBuf, str::FromStr};
+
+use mqtt_publisher::MqttConfig;
+use serde::Deserialize;
+
+mod battery_sim;
+mod reader;
+mod simulator;
+mod solar_reader;
+
+pub use battery_sim::{BatteryConfig, BatterySimConfig};
+pub use reader::{
+    AlarmQuantity, AlarmRule, BaseloadConfig, DEFAULT_METER_ID, MeterConfig, Phase, ReaderConfig,
+};
+pub use simulator::{LoadProfile, SimulatorConfig};
+pub use solar_reader::{InverterRegisters, SolarReaderConfig};
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    reader: reader::ReaderFile,
+    solar_reader: solar_reader::SolarReaderFile,
+    battery_sim: battery_sim::BatterySimFile,
+    simulator: simulator::SimulatorFile,
+}
+
+impl File {
+    fn load() -> Result<Self, Error> {
+        let (path, required) = match env::var("CONFIG_FILE") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default());
+            }
+            Err(error) => return Err(Error::Read { path, error }),
+        };
+
+        toml::from_str(&contents).map_err(|error| Error::Parse { path, error })
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum Error {
+    #[error("Could not read config file {path}: {error}")]
+    Read { path: PathBuf, error: io::Error },
+    #[error("Invalid config file {path}: {error}")]
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    #[error("Invalid value for `{key}`: {message}")]
+    Invalid { key: String, message: String },
+    #[error("Missing `{key}`, set it in the config file or with the {var} env var")]
+    Missing { key: String, var: &'static str },
+}
+
+/// The binaries return errors from `main`, which prints them with `Debug`
+impl std::fmt::Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+/// Replaces `value` with the parsed env var, if it is set and not empty
+fn env_override<T: FromStr>(
+    value: &mut Option<T>,
+    var: &'static str,
+    key: &str,
+) -> Result<(), Error>
+where
+    T::Err: Display,
+{
+    if let Ok(env_value) = env::var(var)
+        && !env_value.is_empty()
+    {
+        *value = Some(parse(&env_value, &format!("{key} (from {var})"))?);
+    }
+    Ok(())
+}
+
+fn parse<T: FromStr>(value: &str, key: &str) -> Result<T, Error>
+where
+    T::Err: Display,
+{
+    value.parse().map_err(|e: T::Err| Error::Invalid {
+        key: key.into(),
+        message: e.to_string(),
+    })
+}
+
+fn required<T>(value: Option<T>, key: &str, var: &'static str) -> Result<T, Error> {
+    value.ok_or_else(|| Error::Missing {
+        key: key.into(),
+        var,
+    })
+}
+
+/// The grid meter Modbus server a binary runs, see the `grid-meter` crate
+#[derive(Debug, Clone)]
+pub struct GridMeterConfig {
+    pub address: SocketAddr,
+    pub measuring_system: grid_meter::MeasuringSystem,
+    /// Null terminated and padded to the 14 bytes the grid meter reports
+    pub serial_number: &'static [u8],
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GridMeterFile {
+    address: Option<SocketAddr>,
+    measuring_system: Option<String>,
+    serial_number: Option<String>,
+}
+
+impl GridMeterFile {
+    fn resolve(
+        mut self,
+        section: &str,
+        default_measuring_system: grid_meter::MeasuringSystem,
+        default_serial_number: &str,
+    ) -> Result<GridMeterConfig, Error> {
+        let key = format!("{section}.grid_meter.address");
+        env_override(&mut self.address, "GRID_METER_ADDRESS", &key)?;
+        let address = required(self.address, &key, "GRID_METER_ADDRESS")?;
+
+        self.build(
+            address,
+            section,
+            default_measuring_system,
+            default_serial_number,
+        )
+    }
+
+    /// Like [Self::resolve] without env vars, and the server is disabled when no address is set
+    fn resolve_optional(
+        self,
+        section: &str,
+        default_measuring_system: grid_meter::MeasuringSystem,
+        default_serial_number: &str,
+    ) -> Result<Option<GridMeterConfig>, Error> {
+        match self.address {
+            Some(address) => self
+                .build(
+                    address,
+                    section,
+                    default_measuring_system,
+                    default_serial_number,
+                )
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn build(
+        self,
+        address: SocketAddr,
+        section: &str,
+        default_measuring_system: grid_meter::MeasuringSystem,
+        default_serial_number: &str,
+    ) -> Result<GridMeterConfig, Error> {
+        let key = |name| format!("{section}.grid_meter.{name}");
+
+        let measuring_system = match self.measuring_system {
+            Some(measuring_system) => parse(&measuring_system, &key("measuring_system"))?,
+            None => default_measuring_system,
+        };
+
+        let serial_number = self
+            .serial_number
+            .as_deref()
+            .unwrap_or(default_serial_number);
+        if !serial_number.is_ascii() || serial_number.len() > 13 {
+            return Err(Error::Invalid {
+                key: key("serial_number"),
+                message: "must be at most 13 ASCII characters".into(),
+            });
+        }
+        let mut serial_number = serial_number.as_bytes().to_vec();
+        serial_number.resize(14, 0);
+
+        Ok(GridMeterConfig {
+            address,
+            measuring_system,
+            serial_number: serial_number.leak(),
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MqttFile {
+    host: Option<String>,
+    port: Option<u16>,
+    client_id: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    topic_prefix: Option<String>,
+    qos: Option<u8>,
+    retain: Option<bool>,
+}
+
+impl MqttFile {
+    /// MQTT is disabled when no host is set
+    fn resolve(
+        mut self,
+        section: &str,
+        default_client_id: &str,
+        default_topic_prefix: &str,
+    ) -> Result<Option<MqttConfig>, Error> {
+        let key = |name| format!("{section}.mqtt.{name}");
+        env_override(&mut self.host, "MQTT_HOST", &key("host"))?;
+        env_override(&mut self.port, "MQTT_PORT", &key("port"))?;
+        env_override(&mut self.client_id, "MQTT_CLIENT_ID", &key("client_id"))?;
+        env_override(&mut self.username, "MQTT_USERNAME", &key("username"))?;
+        env_override(&mut self.password, "MQTT_PASSWORD", &key("password"))?;
+        env_override(
+            &mut self.topic_prefix,
+            "MQTT_TOPIC_PREFIX",
+            &key("topic_prefix"),
+        )?;
+        env_override(&mut self.qos, "MQTT_QOS", &key("qos"))?;
+        env_override(&mut self.retain, "MQTT_RETAIN", &key("retain"))?;
+
+        let Some(host) = self.host else {
+            return Ok(None);
+        };
+
+        Ok(Some(MqttConfig {
+            host,
+            port: self.port.unwrap_or(1883),
+            client_id: self.client_id.unwrap_or_else(|| default_client_id.into()),
+            credentials: self.username.zip(self.password),
+            topic_prefix: self
+                .topic_prefix
+                .unwrap_or_else(|| default_topic_prefix.into()),
+            qos: rumqttc::qos(self.qos.unwrap_or(0)).map_err(|e| Error::Invalid {
+                key: key("qos"),
+                message: e.to_string(),
+            })?,
+            retain: self.retain.unwrap_or(true),
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotificationFile {
+    url: Option<String>,
+    format: Option<String>,
+    topic: Option<String>,
+    token: Option<String>,
+    command: Option<Vec<String>>,
+    #[serde(default)]
+    events: Vec<String>,
+    title: Option<String>,
+    message: Option<String>,
+    retries: Option<u32>,
+}
+
+/// Resolves `[[<section>.notifications]]`, `known_events` are the events the binary sends
+fn resolve_notifications(
+    notifications: Vec<NotificationFile>,
+    section: &str,
+    known_events: &[&str],
+) -> Result<Vec<notifier::SinkConfig>, Error> {
+    notifications
+        .into_iter()
+        .enumerate()
+        .map(|(i, notification)| {
+            let key = |name| format!("{section}.notifications[{i}].{name}");
+
+            let target = match (notification.url, notification.command) {
+                (Some(url), None) => {
+                    let format = match notification.format.as_deref() {
+                        None | Some("json") => notifier::WebhookFormat::Json,
+                        Some("ntfy") => notifier::WebhookFormat::Ntfy {
+                            topic: notification.topic.ok_or_else(|| Error::Invalid {
+                                key: key("topic"),
+                                message: "is required for ntfy".into(),
+                            })?,
+                        },
+                        Some("gotify") => notifier::WebhookFormat::Gotify,
+                        Some(format) => {
+                            return Err(Error::Invalid {
+                                key: key("format"),
+                                message: format!(
+                                    "unknown format {format:?}, expected json, ntfy or gotify"
+                                ),
+                            });
+                        }
+                    };
+                    notifier::Target::Webhook {
+                        url,
+                        format,
+                        token: notification.token,
+                    }
+                }
+                (None, Some(command)) if !command.is_empty() => notifier::Target::Command(command),
+                _ => {
+                    return Err(Error::Invalid {
+                        key: key("url"),
+                        message: "set either `url` or a non-empty `command`".into(),
+                    });
+                }
+            };
+
+            if let Some(event) = notification
+                .events
+                .iter()
+                .find(|event| !known_events.contains(&event.as_str()))
+            {
+                return Err(Error::Invalid {
+                    key: key("events"),
+                    message: format!(
+                        "unknown event {event:?}, expected one of {}",
+                        known_events.join(", ")
+                    ),
+                });
+            }
+
+            Ok(notifier::SinkConfig {
+                target,
+                events: notification.events,
+                title: notification.title,
+                message: notification.message,
+                retries: notification.retries.unwrap_or(3),
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TariffFile {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    import_high: Option<f32>,
+    import_low: Option<f32>,
+    export_high: Option<f32>,
+    export_low: Option<f32>,
+    import_factor: Option<f32>,
+    import_markup: Option<f32>,
+    export_factor: Option<f32>,
+    export_markup: Option<f32>,
+}
+
+impl TariffFile {
+    /// Resolves `[<section>.tariff]`, `None` when it's not set
+    fn resolve(self, section: &str) -> Result<Option<prices::Tariff>, Error> {
+        let key = |name| format!("{section}.tariff.{name}");
+        let not_for = |name, kind| Error::Invalid {
+            key: key(name),
+            message: format!("doesn't apply to a {kind} tariff"),
+        };
+
+        match self.kind.as_deref() {
+            None if self == Self::default() => Ok(None),
+            Some("fixed") => {
+                if self.import_factor.is_some() || self.import_markup.is_some() {
+                    return Err(not_for("import_factor", "fixed"));
+                }
+                if self.export_factor.is_some() || self.export_markup.is_some() {
+                    return Err(not_for("export_factor", "fixed"));
+                }
+                let import_high = self.import_high.ok_or_else(|| Error::Invalid {
+                    key: key("import_high"),
+                    message: "is required for a fixed tariff".into(),
+                })?;
+                let export_high = self.export_high.unwrap_or(0.0);
+
+                Ok(Some(prices::Tariff::Fixed {
+                    import_high,
+                    import_low: self.import_low.unwrap_or(import_high),
+                    export_high,
+                    export_low: self.export_low.unwrap_or(export_high),
+                }))
+            }
+            Some("dynamic") => {
+                if self.import_high.is_some() || self.import_low.is_some() {
+                    return Err(not_for("import_high", "dynamic"));
+                }
+                if self.export_high.is_some() || self.export_low.is_some() {
+                    return Err(not_for("export_high", "dynamic"));
+                }
+
+                Ok(Some(prices::Tariff::Dynamic {
+                    import_factor: self.import_factor.unwrap_or(1.0),
+                    import_markup: self.import_markup.unwrap_or(0.0),
+                    export_factor: self.export_factor.unwrap_or(1.0),
+                    export_markup: self.export_markup.unwrap_or(0.0),
+                }))
+            }
+            kind => Err(Error::Invalid {
+                key: key("type"),
+                message: format!("expected fixed or dynamic, got {kind:?}"),
+            }),
+        }
+    }
+}
+
+/// How a binary logs
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// `tracing_subscriber::EnvFilter` directives, e.g. `info` or `info,reader=debug`
+    pub level: String,
+    /// One json object per line instead of human readable lines
+    pub json: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogFile {
+    level: Option<String>,
+    format: Option<String>,
+}
+
+impl LogFile {
+    fn resolve(mut self, section: &str) -> Result<LogConfig, Error> {
+        let key = |name| format!("{section}.log.{name}");
+        env_override(&mut self.level, "LOG_LEVEL", &key("level"))?;
+        env_override(&mut self.format, "LOG_FORMAT", &key("format"))?;
+
+        let json = match self.format.as_deref() {
+            None | Some("text") => false,
+            Some("json") => true,
+            Some(format) => {
+                return Err(Error::Invalid {
+                    key: key("format"),
+                    message: format!("unknown format {format:?}, expected text or json"),
+                });
+            }
+        };
+
+        Ok(LogConfig {
+            level: self.level.unwrap_or_else(|| "info".into()),
+            json,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    use super::*;
+
+    /// Env vars are shared by all tests, so the tests that read or set them take turns
+    static ENV: Mutex<()> = Mutex::new(());
+
+    pub(crate) fn lock_env() -> MutexGuard<'static, ()> {
+        ENV.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn file(toml: &str) -> File {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// The key of the invalid value `result` failed on
+    #[track_caller]
+    pub(crate) fn invalid_key<T: std::fmt::Debug>(result: Result<T, Error>) -> String {
+        match result {
+            Err(Error::Invalid { key, .. }) => key,
+            result => panic!("expected an invalid value, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn env_overrides_the_file() {
+        let _env = lock_env();
+        let mqtt = |toml| {
+            toml::from_str::<MqttFile>(toml)
+                .unwrap()
+                .resolve("reader", "p1-reader", "p1")
+        };
+
+        // SAFETY: the other tests that touch env vars wait for the lock
+        unsafe { env::set_var("MQTT_PORT", "1884") };
+        let config = mqtt("host = 'broker'\nport = 1883").unwrap().unwrap();
+        assert_eq!(config.port, 1884);
+
+        // An empty env var is the same as no env var
+        unsafe { env::set_var("MQTT_PORT", "") };
+        let config = mqtt("host = 'broker'\nport = 1883").unwrap().unwrap();
+        assert_eq!(config.port, 1883);
+
+        unsafe { env::set_var("MQTT_PORT", "not a port") };
+        assert_eq!(
+            invalid_key(mqtt("host = 'broker'")),
+            "reader.mqtt.port (from MQTT_PORT)"
+        );
+
+        unsafe { env::remove_var("MQTT_PORT") };
+        assert_eq!(
+            invalid_key(mqtt("host = 'broker'\nqos = 3")),
+            "reader.mqtt.qos"
+        );
+    }
+
+    #[test]
+    fn invalid_shared_sections() {
+        let grid_meter = |toml| {
+            toml::from_str::<GridMeterFile>(toml)
+                .unwrap()
+                .resolve_optional(
+                    "reader",
+                    grid_meter::MeasuringSystem::Setup3PN,
+                    "BY24600320011",
+                )
+        };
+        assert!(grid_meter("").unwrap().is_none());
+        assert_eq!(
+            invalid_key(grid_meter(
+                "address = '127.0.0.1:5020'\nserial_number = 'FAR TOO LONG SERIAL'"
+            )),
+            "reader.grid_meter.serial_number"
+        );
+        assert_eq!(
+            invalid_key(grid_meter(
+                "address = '127.0.0.1:5020'\nmeasuring_system = 'nonsense'"
+            )),
+            "reader.grid_meter.measuring_system"
+        );
+
+        let notifications = |toml| {
+            #[derive(Deserialize)]
+            struct Notifications {
+                notifications: Vec<NotificationFile>,
+            }
+            let file: Notifications = toml::from_str(toml).unwrap();
+            resolve_notifications(file.notifications, "reader", &["p1_stalled"])
+        };
+        for (toml, key) in [
+            ("[[notifications]]", "reader.notifications[0].url"),
+            (
+                "[[notifications]]\ncommand = []",
+                "reader.notifications[0].url",
+            ),
+            (
+                "[[notifications]]\nurl = 'http://ntfy.sh'\nformat = 'ntfy'",
+                "reader.notifications[0].topic",
+            ),
+            (
+                "[[notifications]]\nurl = 'http://localhost'\nformat = 'xml'",
+                "reader.notifications[0].format",
+            ),
+            (
+                "[[notifications]]\ncommand = ['true']\n\n[[notifications]]\ncommand = ['true']\nevents = ['p1_stalled', 'solar_eclipse']",
+                "reader.notifications[1].events",
+            ),
+        ] {
+            assert_eq!(invalid_key(notifications(toml)), key, "{toml}");
+        }
+
+        let log = |toml| toml::from_str::<LogFile>(toml).unwrap().resolve("reader");
+        let _env = lock_env();
+        assert_eq!(invalid_key(log("format = 'xml'")), "reader.log.format");
+    }
+
+    #[test]
+    fn tariffs() {
+        let tariff = |toml| {
+            toml::from_str::<TariffFile>(toml)
+                .unwrap()
+                .resolve("reader")
+        };
+
+        assert_eq!(tariff("").unwrap(), None);
+        assert_eq!(
+            tariff("type = 'fixed'\nimport_high = 0.3\nexport_high = 0.1").unwrap(),
+            Some(prices::Tariff::Fixed {
+                import_high: 0.3,
+                import_low: 0.3,
+                export_high: 0.1,
+                export_low: 0.1,
+            })
+        );
+
+        for (toml, key) in [
+            ("import_high = 0.3", "reader.tariff.type"),
+            ("type = 'hourly'", "reader.tariff.type"),
+            ("type = 'fixed'", "reader.tariff.import_high"),
+            (
+                "type = 'fixed'\nimport_high = 0.3\nimport_markup = 0.02",
+                "reader.tariff.import_factor",
+            ),
+            (
+                "type = 'dynamic'\nexport_low = 0.1",
+                "reader.tariff.export_high",
+            ),
+        ] {
+            assert_eq!(invalid_key(tariff(toml)), key, "{toml}");
+        }
+    }
+}
@@ -0,0 +1,631 @@
+use std::{collections::HashSet, net::SocketAddr, path::PathBuf, time::Duration};
+
+use chrono_tz::Tz;
+use mqtt_publisher::MqttConfig;
+use serde::Deserialize;
+
+use crate::{
+    Error, File, GridMeterConfig, GridMeterFile, LogConfig, LogFile, MqttFile, NotificationFile,
+    TariffFile, env_override, parse, required, resolve_notifications,
+};
+
+/// The meter ID of the only meter when `[[reader.meters]]` isn't used, and of all data from before meter IDs
+pub const DEFAULT_METER_ID: &str = "main";
+
+/// What the reader sends notifications about
+const NOTIFICATION_EVENTS: &[&str] = &[
+    "alarm_raised",
+    "alarm_cleared",
+    "p1_stalled",
+    "p1_resumed",
+    "database_failed",
+    "database_recovered",
+    "capacity_peak",
+    "peak_warning",
+    "baseload_increased",
+];
+
+/// The default grid meter settings of every meter
+const DEFAULT_MEASURING_SYSTEM: grid_meter::MeasuringSystem = grid_meter::MeasuringSystem::Setup3PN;
+const DEFAULT_SERIAL_NUMBER: &str = "BY24600320011";
+
+/// `[reader]`
+#[derive(Debug, Clone)]
+pub struct ReaderConfig {
+    pub database_url: String,
+    /// The P1 ports that are read, at least one
+    pub meters: Vec<MeterConfig>,
+    /// The timezone the meter's clock is in
+    pub meter_timezone: Tz,
+    /// Directory telegrams are spooled to until they are in the database
+    pub spool_dir: PathBuf,
+    /// Write to the database as soon as this many telegrams are waiting
+    pub batch_size: usize,
+    /// Write whatever is waiting to the database, and sync the spool to disk, at least this often
+    pub batch_interval: Duration,
+    /// Warn when the meter's clock is off by more than this
+    pub max_clock_drift: Duration,
+    /// The HTTP API is only served when this is set
+    pub http_address: Option<SocketAddr>,
+    /// Raw electricity data older than this is deleted once it's rolled up
+    pub raw_retention_days: Option<u32>,
+    /// Only log what retention would delete
+    pub retention_dry_run: bool,
+    /// Track the monthly quarter-hour peaks the Flemish capacity tariff bills, and warn about new ones
+    pub capacity_tariff: bool,
+    /// The rollups have the cost of the imported and exported energy when this is set
+    pub tariff: Option<prices::Tariff>,
+    /// ENTSO-E XML and CSV files with day-ahead prices in here are imported, for dynamic tariffs
+    pub prices_dir: Option<PathBuf>,
+    /// How the always-on consumption is worked out every night
+    pub baseload: BaseloadConfig,
+    /// Checked against every telegram of the meters they apply to
+    pub alarms: Vec<AlarmRule>,
+    /// Where notifications about alarms, stalls and the database go
+    pub notifications: Vec<notifier::SinkConfig>,
+    /// MQTT is disabled when `None`
+    pub mqtt: Option<MqttConfig>,
+    pub log: LogConfig,
+}
+
+/// `[[reader.meters]]`, or the top level `serial_port`, `baud_rate` and `[reader.grid_meter]` for a single meter
+#[derive(Debug, Clone)]
+pub struct MeterConfig {
+    /// Stored with all data of the meter
+    pub id: String,
+    /// The serial port the P1 cable is connected to
+    pub serial_port: String,
+    pub baud_rate: u32,
+    /// The meter is only exposed as an emulated grid meter when this is set
+    pub grid_meter: Option<GridMeterConfig>,
+}
+
+/// `[reader.baseload]`
+#[derive(Debug, Clone)]
+pub struct BaseloadConfig {
+    /// The local hour the night starts, the baseload is the lowest consumption of the night
+    pub night_start_hour: u32,
+    /// The local hour the night ends, on the next day when it's before `night_start_hour`
+    pub night_end_hour: u32,
+    /// The solar-reader's database, to add the PV output to the consumption of `solar_meter`
+    pub solar_database_url: Option<String>,
+    /// The meter the PV inverter is behind
+    pub solar_meter: String,
+    /// A night's baseload is compared to the median of this many days before
+    pub reference_days: u32,
+    /// kW, a baseload this much above the median is flagged as an increase
+    pub min_increase: f32,
+}
+
+/// `[[reader.alarms]]`, a threshold on one of the per-phase values of a meter
+#[derive(Debug, Clone)]
+pub struct AlarmRule {
+    /// Identifies the alarm in events
+    pub name: String,
+    /// Only this meter, or all meters when `None`
+    pub meter: Option<String>,
+    pub quantity: AlarmQuantity,
+    /// Only this phase, or every phase on its own when `None`
+    pub phase: Option<Phase>,
+    /// The alarm is raised when the value is above this...
+    pub above: Option<f32>,
+    /// ...or below this
+    pub below: Option<f32>,
+    /// ...for at least this long
+    pub duration: Duration,
+    /// The alarm is only cleared once the value is this far back inside the threshold
+    pub hysteresis: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmQuantity {
+    /// A
+    Current,
+    /// V
+    Voltage,
+    /// kW
+    PowerImport,
+    /// kW
+    PowerExport,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Phase {
+    L1,
+    L2,
+    L3,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 3] = [Phase::L1, Phase::L2, Phase::L3];
+
+    /// The index into the per-phase arrays of a telegram
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ReaderFile {
+    database_url: Option<String>,
+    serial_port: Option<String>,
+    baud_rate: Option<u32>,
+    meter_timezone: Option<String>,
+    spool_dir: Option<PathBuf>,
+    batch_size: Option<usize>,
+    batch_interval_seconds: Option<u64>,
+    max_clock_drift_seconds: Option<u64>,
+    http_address: Option<SocketAddr>,
+    raw_retention_days: Option<u32>,
+    retention_dry_run: Option<bool>,
+    capacity_tariff: Option<bool>,
+    prices_dir: Option<PathBuf>,
+    tariff: TariffFile,
+    baseload: BaseloadFile,
+    grid_meter: GridMeterFile,
+    meters: Vec<MeterFile>,
+    alarms: Vec<AlarmFile>,
+    notifications: Vec<NotificationFile>,
+    mqtt: MqttFile,
+    log: LogFile,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeterFile {
+    id: String,
+    serial_port: String,
+    baud_rate: Option<u32>,
+    #[serde(default)]
+    grid_meter: GridMeterFile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BaseloadFile {
+    night_start_hour: Option<u32>,
+    night_end_hour: Option<u32>,
+    solar_database_url: Option<String>,
+    solar_meter: Option<String>,
+    reference_days: Option<u32>,
+    min_increase: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AlarmFile {
+    name: String,
+    meter: Option<String>,
+    quantity: AlarmQuantity,
+    phase: Option<Phase>,
+    above: Option<f32>,
+    below: Option<f32>,
+    #[serde(default)]
+    for_seconds: u64,
+    #[serde(default)]
+    hysteresis: f32,
+}
+
+impl ReaderConfig {
+    pub fn load() -> Result<Self, Error> {
+        Self::resolve(File::load()?.reader)
+    }
+
+    fn resolve(mut file: ReaderFile) -> Result<Self, Error> {
+        let meters = if file.meters.is_empty() {
+            env_override(&mut file.serial_port, "SERIAL_PORT", "reader.serial_port")?;
+
+            vec![MeterConfig {
+                id: DEFAULT_METER_ID.into(),
+                serial_port: file.serial_port.unwrap_or_else(|| "/dev/ttyUSB0".into()),
+                baud_rate: file.baud_rate.unwrap_or(115_200),
+                grid_meter: Some(file.grid_meter.resolve(
+                    "reader",
+                    DEFAULT_MEASURING_SYSTEM,
+                    DEFAULT_SERIAL_NUMBER,
+                )?),
+            }]
+        } else {
+            if file.serial_port.is_some()
+                || file.baud_rate.is_some()
+                || file.grid_meter != GridMeterFile::default()
+            {
+                return Err(Error::Invalid {
+                    key: "reader.meters".into(),
+                    message: "can't be combined with `reader.serial_port`, `reader.baud_rate` or `[reader.grid_meter]`, set them per meter instead".into(),
+                });
+            }
+            resolve_meters(file.meters)?
+        };
+
+        env_override(
+            &mut file.database_url,
+            "DATABASE_URL",
+            "reader.database_url",
+        )?;
+        env_override(
+            &mut file.meter_timezone,
+            "METER_TIMEZONE",
+            "reader.meter_timezone",
+        )?;
+        env_override(&mut file.spool_dir, "SPOOL_DIR", "reader.spool_dir")?;
+        env_override(&mut file.batch_size, "BATCH_SIZE", "reader.batch_size")?;
+        env_override(
+            &mut file.batch_interval_seconds,
+            "BATCH_INTERVAL_SECONDS",
+            "reader.batch_interval_seconds",
+        )?;
+        // The database writer would keep waking up to write nothing
+        if file.batch_interval_seconds == Some(0) {
+            return Err(Error::Invalid {
+                key: "reader.batch_interval_seconds".into(),
+                message: "must be at least 1".into(),
+            });
+        }
+        env_override(
+            &mut file.max_clock_drift_seconds,
+            "MAX_CLOCK_DRIFT_SECONDS",
+            "reader.max_clock_drift_seconds",
+        )?;
+        env_override(
+            &mut file.http_address,
+            "HTTP_ADDRESS",
+            "reader.http_address",
+        )?;
+        env_override(
+            &mut file.raw_retention_days,
+            "RAW_RETENTION_DAYS",
+            "reader.raw_retention_days",
+        )?;
+        env_override(
+            &mut file.retention_dry_run,
+            "RETENTION_DRY_RUN",
+            "reader.retention_dry_run",
+        )?;
+        env_override(
+            &mut file.capacity_tariff,
+            "CAPACITY_TARIFF",
+            "reader.capacity_tariff",
+        )?;
+        env_override(&mut file.prices_dir, "PRICES_DIR", "reader.prices_dir")?;
+
+        env_override(
+            &mut file.baseload.solar_database_url,
+            "SOLAR_DATABASE_URL",
+            "reader.baseload.solar_database_url",
+        )?;
+        let baseload = resolve_baseload(file.baseload, &meters)?;
+        let alarms = resolve_alarms(file.alarms, &meters)?;
+
+        Ok(Self {
+            database_url: required(file.database_url, "reader.database_url", "DATABASE_URL")?,
+            meters,
+            meter_timezone: parse(
+                file.meter_timezone.as_deref().unwrap_or("Europe/Amsterdam"),
+                "reader.meter_timezone",
+            )?,
+            spool_dir: file.spool_dir.unwrap_or_else(|| "spool".into()),
+            batch_size: file.batch_size.unwrap_or(60),
+            batch_interval: Duration::from_secs(file.batch_interval_seconds.unwrap_or(10)),
+            max_clock_drift: Duration::from_secs(file.max_clock_drift_seconds.unwrap_or(30)),
+            http_address: file.http_address,
+            raw_retention_days: file.raw_retention_days,
+            retention_dry_run: file.retention_dry_run.unwrap_or(false),
+            capacity_tariff: file.capacity_tariff.unwrap_or(false),
+            tariff: file.tariff.resolve("reader")?,
+            prices_dir: file.prices_dir,
+            baseload,
+            alarms,
+            notifications: resolve_notifications(
+                file.notifications,
+                "reader",
+                NOTIFICATION_EVENTS,
+            )?,
+            log: file.log.resolve("reader")?,
+            mqtt: file.mqtt.resolve("reader", "p1-reader", "p1")?,
+        })
+    }
+}
+
+fn resolve_meters(meters: Vec<MeterFile>) -> Result<Vec<MeterConfig>, Error> {
+    let mut ids = HashSet::new();
+
+    meters
+        .into_iter()
+        .enumerate()
+        .map(|(i, meter)| {
+            let section = format!("reader.meters[{i}]");
+
+            // The ID ends up in MQTT topics and metric labels
+            let valid_id = !meter.id.is_empty()
+                && meter
+                    .id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid_id {
+                return Err(Error::Invalid {
+                    key: format!("{section}.id"),
+                    message: "must be letters, digits, - and _".into(),
+                });
+            }
+            if !ids.insert(meter.id.clone()) {
+                return Err(Error::Invalid {
+                    key: format!("{section}.id"),
+                    message: format!("{:?} is used by more than one meter", meter.id),
+                });
+            }
+
+            Ok(MeterConfig {
+                grid_meter: meter.grid_meter.resolve_optional(
+                    &section,
+                    DEFAULT_MEASURING_SYSTEM,
+                    DEFAULT_SERIAL_NUMBER,
+                )?,
+                id: meter.id,
+                serial_port: meter.serial_port,
+                baud_rate: meter.baud_rate.unwrap_or(115_200),
+            })
+        })
+        .collect()
+}
+
+fn resolve_baseload(
+    baseload: BaseloadFile,
+    meters: &[MeterConfig],
+) -> Result<BaseloadConfig, Error> {
+    let invalid = |name, message: &str| Error::Invalid {
+        key: format!("reader.baseload.{name}"),
+        message: message.into(),
+    };
+
+    let night_start_hour = baseload.night_start_hour.unwrap_or(1);
+    let night_end_hour = baseload.night_end_hour.unwrap_or(5);
+    for (name, hour) in [
+        ("night_start_hour", night_start_hour),
+        ("night_end_hour", night_end_hour),
+    ] {
+        if hour > 23 {
+            return Err(invalid(name, "must be 0 to 23"));
+        }
+    }
+    if night_start_hour == night_end_hour {
+        return Err(invalid(
+            "night_end_hour",
+            "must differ from `night_start_hour`",
+        ));
+    }
+
+    let solar_meter = baseload.solar_meter.unwrap_or_else(|| meters[0].id.clone());
+    if !meters.iter().any(|meter| meter.id == solar_meter) {
+        return Err(invalid(
+            "solar_meter",
+            &format!("there is no meter {solar_meter:?}"),
+        ));
+    }
+
+    let reference_days = baseload.reference_days.unwrap_or(14);
+    if reference_days == 0 {
+        return Err(invalid("reference_days", "must be at least 1"));
+    }
+    let min_increase = baseload.min_increase.unwrap_or(0.03);
+    if min_increase < 0.0 {
+        return Err(invalid("min_increase", "can't be negative"));
+    }
+
+    Ok(BaseloadConfig {
+        night_start_hour,
+        night_end_hour,
+        solar_database_url: baseload.solar_database_url,
+        solar_meter,
+        reference_days,
+        min_increase,
+    })
+}
+
+fn resolve_alarms(alarms: Vec<AlarmFile>, meters: &[MeterConfig]) -> Result<Vec<AlarmRule>, Error> {
+    let mut names = HashSet::new();
+
+    alarms
+        .into_iter()
+        .enumerate()
+        .map(|(i, alarm)| {
+            let key = |name| format!("reader.alarms[{i}].{name}");
+
+            if alarm.name.is_empty() || !names.insert(alarm.name.clone()) {
+                return Err(Error::Invalid {
+                    key: key("name"),
+                    message: "must be set and unique".into(),
+                });
+            }
+            if let Some(meter) = &alarm.meter
+                && !meters.iter().any(|meter_config| &meter_config.id == meter)
+            {
+                return Err(Error::Invalid {
+                    key: key("meter"),
+                    message: format!("there is no meter {meter:?}"),
+                });
+            }
+            if alarm.above.is_none() && alarm.below.is_none() {
+                return Err(Error::Invalid {
+                    key: key("above"),
+                    message: "set `above`, `below` or both".into(),
+                });
+            }
+            if let (Some(above), Some(below)) = (alarm.above, alarm.below)
+                && below + alarm.hysteresis >= above - alarm.hysteresis
+            {
+                return Err(Error::Invalid {
+                    key: key("below"),
+                    message: "must be below `above`, with room for the hysteresis on both".into(),
+                });
+            }
+            if alarm.hysteresis < 0.0 {
+                return Err(Error::Invalid {
+                    key: key("hysteresis"),
+                    message: "can't be negative".into(),
+                });
+            }
+
+            Ok(AlarmRule {
+                name: alarm.name,
+                meter: alarm.meter,
+                quantity: alarm.quantity,
+                phase: alarm.phase,
+                above: alarm.above,
+                below: alarm.below,
+                duration: Duration::from_secs(alarm.for_seconds),
+                hysteresis: alarm.hysteresis,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{file, invalid_key, lock_env};
+
+    const METERS: &str = "
+        [reader]
+        database_url = 'postgres://localhost/p1'
+
+        [[reader.meters]]
+        id = 'main'
+        serial_port = '/dev/ttyUSB0'
+
+        [[reader.meters]]
+        id = 'garage'
+        serial_port = '/dev/ttyUSB1'
+    ";
+
+    fn resolve(toml: &str) -> Result<ReaderConfig, Error> {
+        ReaderConfig::resolve(file(&format!("{METERS}\n{toml}")).reader)
+    }
+
+    #[test]
+    fn defaults() {
+        let _env = lock_env();
+        let config = resolve("").unwrap();
+
+        assert_eq!(config.meters.len(), 2);
+        assert!(config.meters[1].grid_meter.is_none());
+        assert_eq!(config.baseload.solar_meter, "main");
+        assert_eq!(
+            (
+                config.baseload.night_start_hour,
+                config.baseload.night_end_hour
+            ),
+            (1, 5)
+        );
+        assert!(config.tariff.is_none());
+    }
+
+    #[test]
+    fn invalid_meters() {
+        let _env = lock_env();
+        let single = |toml: &str| ReaderConfig::resolve(file(toml).reader);
+
+        assert_eq!(
+            invalid_key(single(
+                "[reader]\nserial_port = '/dev/ttyUSB0'\n\n[[reader.meters]]\nid = 'main'\nserial_port = '/dev/ttyUSB1'"
+            )),
+            "reader.meters"
+        );
+        assert_eq!(
+            invalid_key(single(
+                "[[reader.meters]]\nid = 'main meter'\nserial_port = '/dev/ttyUSB0'"
+            )),
+            "reader.meters[0].id"
+        );
+        assert_eq!(
+            invalid_key(resolve(
+                "[[reader.meters]]\nid = 'garage'\nserial_port = '/dev/ttyUSB2'"
+            )),
+            "reader.meters[2].id"
+        );
+    }
+
+    #[test]
+    fn invalid_alarms() {
+        let _env = lock_env();
+        for (alarm, key) in [
+            ("name = ''\nabove = 25.0", "reader.alarms[0].name"),
+            (
+                "name = 'overload'\nabove = 25.0\nmeter = 'attic'",
+                "reader.alarms[0].meter",
+            ),
+            ("name = 'overload'", "reader.alarms[0].above"),
+            (
+                "name = 'overload'\nabove = 25.0\nbelow = 24.0\nhysteresis = 1.0",
+                "reader.alarms[0].below",
+            ),
+            (
+                "name = 'overload'\nabove = 25.0\nhysteresis = -1.0",
+                "reader.alarms[0].hysteresis",
+            ),
+        ] {
+            let toml = format!("[[reader.alarms]]\nquantity = 'current'\n{alarm}");
+            assert_eq!(invalid_key(resolve(&toml)), key, "{alarm}");
+        }
+
+        let toml = "[[reader.alarms]]\nname = 'overload'\nquantity = 'current'\nabove = 25.0\n\n[[reader.alarms]]\nname = 'overload'\nquantity = 'voltage'\nbelow = 207.0";
+        assert_eq!(invalid_key(resolve(toml)), "reader.alarms[1].name");
+    }
+
+    #[test]
+    fn invalid_batch_interval() {
+        let _env = lock_env();
+        // Into `[reader]` instead of after the meters
+        let reader = |toml: &str| {
+            ReaderConfig::resolve(
+                file(&METERS.replacen("[reader]", &format!("[reader]\n{toml}"), 1)).reader,
+            )
+        };
+        assert_eq!(
+            invalid_key(reader("batch_interval_seconds = 0")),
+            "reader.batch_interval_seconds"
+        );
+
+        // SAFETY: the other tests that touch env vars wait for the lock
+        unsafe { std::env::set_var("BATCH_INTERVAL_SECONDS", "0") };
+        let result = reader("batch_interval_seconds = 5");
+        unsafe { std::env::remove_var("BATCH_INTERVAL_SECONDS") };
+        assert_eq!(invalid_key(result), "reader.batch_interval_seconds");
+    }
+
+    #[test]
+    fn invalid_baseload_and_tariff() {
+        let _env = lock_env();
+        for (toml, key) in [
+            (
+                "[reader.baseload]\nnight_start_hour = 24",
+                "reader.baseload.night_start_hour",
+            ),
+            (
+                "[reader.baseload]\nnight_end_hour = 24",
+                "reader.baseload.night_end_hour",
+            ),
+            (
+                "[reader.baseload]\nnight_start_hour = 3\nnight_end_hour = 3",
+                "reader.baseload.night_end_hour",
+            ),
+            (
+                "[reader.baseload]\nsolar_meter = 'attic'",
+                "reader.baseload.solar_meter",
+            ),
+            (
+                "[reader.baseload]\nreference_days = 0",
+                "reader.baseload.reference_days",
+            ),
+            (
+                "[reader.baseload]\nmin_increase = -0.1",
+                "reader.baseload.min_increase",
+            ),
+            ("[reader.tariff]\ntype = 'hourly'", "reader.tariff.type"),
+        ] {
+            assert_eq!(invalid_key(resolve(toml)), key, "{toml}");
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use crate::{Error, File, env_override, parse};
+
+/// `[simulator]`
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    /// Time between telegrams, DSMR 5 meters send one every second
+    pub interval: Duration,
+    /// The timezone the simulated meter's clock is in
+    pub meter_timezone: Tz,
+    pub equipment_identifier: String,
+    /// 1 or 3
+    pub phases: u8,
+    pub load_profile: LoadProfile,
+    /// kW that is always used
+    pub base_load_kw: f64,
+    /// kW on top of the base load at the busiest time of day
+    pub peak_load_kw: f64,
+    /// kW the solar panels deliver at noon on a clear summer day, on L1. 0 disables them.
+    pub pv_peak_kw: f64,
+    /// Simulate a gas meter on M-Bus channel 1
+    pub gas: bool,
+    /// Chance of a power failure starting, per telegram
+    pub power_failure_chance: f64,
+    /// Power failures last up to this long. Failures of 3 minutes or more are long ones and end up in the log.
+    pub max_power_failure: Duration,
+    /// Chance of a telegram being damaged so its CRC doesn't match, per telegram
+    pub corrupt_chance: f64,
+    /// Makes runs reproducible when set
+    pub seed: Option<u64>,
+}
+
+/// The shape of the load over a day
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadProfile {
+    /// Only the base load
+    Flat,
+    /// Peaks in the morning and the evening, with appliances switching on and off
+    Household,
+    /// Busy during office hours on weekdays
+    Office,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SimulatorFile {
+    interval_seconds: Option<f64>,
+    meter_timezone: Option<String>,
+    equipment_identifier: Option<String>,
+    phases: Option<u8>,
+    load_profile: Option<LoadProfile>,
+    base_load_kw: Option<f64>,
+    peak_load_kw: Option<f64>,
+    pv_peak_kw: Option<f64>,
+    gas: Option<bool>,
+    power_failure_chance: Option<f64>,
+    max_power_failure_seconds: Option<u64>,
+    corrupt_chance: Option<f64>,
+    seed: Option<u64>,
+}
+
+impl SimulatorConfig {
+    pub fn load() -> Result<Self, Error> {
+        Self::resolve(File::load()?.simulator)
+    }
+
+    fn resolve(mut file: SimulatorFile) -> Result<Self, Error> {
+        env_override(&mut file.seed, "SIMULATOR_SEED", "simulator.seed")?;
+
+        let phases = file.phases.unwrap_or(3);
+        if phases != 1 && phases != 3 {
+            return Err(Error::Invalid {
+                key: "simulator.phases".into(),
+                message: "must be 1 or 3".into(),
+            });
+        }
+
+        let interval_seconds = file.interval_seconds.unwrap_or(1.0);
+        if !interval_seconds.is_finite() || interval_seconds <= 0.0 {
+            return Err(Error::Invalid {
+                key: "simulator.interval_seconds".into(),
+                message: "must be more than 0".into(),
+            });
+        }
+
+        let equipment_identifier = file
+            .equipment_identifier
+            .unwrap_or_else(|| "SIM0000000000001".into());
+        if !equipment_identifier.is_ascii() || equipment_identifier.len() > 48 {
+            return Err(Error::Invalid {
+                key: "simulator.equipment_identifier".into(),
+                message: "must be at most 48 ASCII characters".into(),
+            });
+        }
+
+        Ok(Self {
+            interval: Duration::from_secs_f64(interval_seconds),
+            meter_timezone: parse(
+                file.meter_timezone.as_deref().unwrap_or("Europe/Amsterdam"),
+                "simulator.meter_timezone",
+            )?,
+            equipment_identifier,
+            phases,
+            load_profile: file.load_profile.unwrap_or(LoadProfile::Household),
+            base_load_kw: file.base_load_kw.unwrap_or(0.3),
+            peak_load_kw: file.peak_load_kw.unwrap_or(2.5),
+            pv_peak_kw: file.pv_peak_kw.unwrap_or(4.0),
+            gas: file.gas.unwrap_or(true),
+            power_failure_chance: chance(
+                file.power_failure_chance.unwrap_or(0.0),
+                "simulator.power_failure_chance",
+            )?,
+            max_power_failure: Duration::from_secs(file.max_power_failure_seconds.unwrap_or(600)),
+            corrupt_chance: chance(
+                file.corrupt_chance.unwrap_or(0.0),
+                "simulator.corrupt_chance",
+            )?,
+            seed: file.seed,
+        })
+    }
+}
+
+fn chance(value: f64, key: &str) -> Result<f64, Error> {
+    if !(0.0..=1.0).contains(&value) {
+        return Err(Error::Invalid {
+            key: key.into(),
+            message: "must be between 0 and 1".into(),
+        });
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{file, invalid_key, lock_env};
+
+    #[test]
+    fn invalid_values() {
+        let _env = lock_env();
+        for (toml, key) in [
+            ("phases = 2", "simulator.phases"),
+            ("interval_seconds = 0.0", "simulator.interval_seconds"),
+            (
+                "equipment_identifier = 'Ø'",
+                "simulator.equipment_identifier",
+            ),
+            (
+                "meter_timezone = 'Mars/Olympus'",
+                "simulator.meter_timezone",
+            ),
+            ("corrupt_chance = 1.5", "simulator.corrupt_chance"),
+        ] {
+            let config = file(&format!("[simulator]\n{toml}")).simulator;
+            assert_eq!(invalid_key(SimulatorConfig::resolve(config)), key, "{toml}");
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::net::SocketAddr;
+
+use mqtt_publisher::MqttConfig;
+use serde::Deserialize;
+
+use crate::{
+    Error, File, GridMeterConfig, GridMeterFile, LogConfig, LogFile, MqttFile, NotificationFile,
+    env_override, required, resolve_notifications,
+};
+
+/// What the solar reader sends notifications about
+const NOTIFICATION_EVENTS: &[&str] = &[
+    "inverter_disconnected",
+    "inverter_reconnected",
+    "database_failed",
+];
+
+/// The most registers that can be read in one Modbus request
+const MAX_REGISTERS_PER_READ: u16 = 125;
+
+/// `[solar_reader]`
+#[derive(Debug, Clone)]
+pub struct SolarReaderConfig {
+    pub database_url: String,
+    /// Modbus TCP address of the inverter
+    pub inverter_address: SocketAddr,
+    pub inverter_slave_id: u8,
+    pub registers: InverterRegisters,
+    /// Prometheus metrics are only served when this is set
+    pub metrics_address: Option<SocketAddr>,
+    /// Solar data older than this is thinned to one row per minute
+    pub raw_retention_days: Option<u32>,
+    /// Only log what retention would delete
+    pub retention_dry_run: bool,
+    pub grid_meter: GridMeterConfig,
+    /// MQTT is disabled when `None`
+    pub mqtt: Option<MqttConfig>,
+    /// Where notifications about the inverter connection and the database go
+    pub notifications: Vec<notifier::SinkConfig>,
+    pub log: LogConfig,
+}
+
+/// `[solar_reader.registers]`, the holding registers of the inverter values
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InverterRegisters {
+    /// W delivered from the panels
+    pub pv_power: u16,
+    /// 0.1 °C, signed
+    pub inverter_temperature: u16,
+    /// W delivered to the net
+    pub output_power: u16,
+    /// 0.1 V
+    pub grid_voltage: u16,
+    /// 0.01 A
+    pub grid_current: u16,
+    /// W
+    pub grid_power: u16,
+    /// 0.01 kWh, two registers with the high word first
+    pub total_energy: u16,
+}
+
+impl Default for InverterRegisters {
+    fn default() -> Self {
+        Self {
+            pv_power: 0x0109,
+            inverter_temperature: 0x0111,
+            output_power: 0x0113,
+            grid_voltage: 0x0116,
+            grid_current: 0x0117,
+            grid_power: 0x011A,
+            total_energy: 0x0131,
+        }
+    }
+}
+
+impl InverterRegisters {
+    /// The first register and the number of registers to read to get all values in one request
+    pub fn read_range(&self) -> (u16, u16) {
+        let registers = [
+            self.pv_power,
+            self.inverter_temperature,
+            self.output_power,
+            self.grid_voltage,
+            self.grid_current,
+            self.grid_power,
+        ];
+        let first = registers
+            .into_iter()
+            .chain([self.total_energy])
+            .min()
+            .unwrap();
+        let last = registers
+            .into_iter()
+            .chain([self.total_energy.saturating_add(1)])
+            .max()
+            .unwrap();
+        (first, last - first + 1)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SolarReaderFile {
+    database_url: Option<String>,
+    inverter_address: Option<SocketAddr>,
+    inverter_slave_id: Option<u8>,
+    registers: InverterRegisters,
+    metrics_address: Option<SocketAddr>,
+    raw_retention_days: Option<u32>,
+    retention_dry_run: Option<bool>,
+    grid_meter: GridMeterFile,
+    mqtt: MqttFile,
+    notifications: Vec<NotificationFile>,
+    log: LogFile,
+}
+
+impl SolarReaderConfig {
+    pub fn load() -> Result<Self, Error> {
+        Self::resolve(File::load()?.solar_reader)
+    }
+
+    fn resolve(mut file: SolarReaderFile) -> Result<Self, Error> {
+        env_override(
+            &mut file.database_url,
+            "DATABASE_URL",
+            "solar_reader.database_url",
+        )?;
+        env_override(
+            &mut file.inverter_address,
+            "INVERTER_SOCKADDR",
+            "solar_reader.inverter_address",
+        )?;
+        env_override(
+            &mut file.metrics_address,
+            "METRICS_ADDRESS",
+            "solar_reader.metrics_address",
+        )?;
+        env_override(
+            &mut file.raw_retention_days,
+            "RAW_RETENTION_DAYS",
+            "solar_reader.raw_retention_days",
+        )?;
+        env_override(
+            &mut file.retention_dry_run,
+            "RETENTION_DRY_RUN",
+            "solar_reader.retention_dry_run",
+        )?;
+
+        let (_, count) = file.registers.read_range();
+        if count > MAX_REGISTERS_PER_READ {
+            return Err(Error::Invalid {
+                key: "solar_reader.registers".into(),
+                message: format!(
+                    "the registers span {count} registers, at most {MAX_REGISTERS_PER_READ} can be read at once"
+                ),
+            });
+        }
+
+        Ok(Self {
+            database_url: required(
+                file.database_url,
+                "solar_reader.database_url",
+                "DATABASE_URL",
+            )?,
+            inverter_address: required(
+                file.inverter_address,
+                "solar_reader.inverter_address",
+                "INVERTER_SOCKADDR",
+            )?,
+            inverter_slave_id: file.inverter_slave_id.unwrap_or(1),
+            registers: file.registers,
+            metrics_address: file.metrics_address,
+            raw_retention_days: file.raw_retention_days,
+            retention_dry_run: file.retention_dry_run.unwrap_or(false),
+            grid_meter: file.grid_meter.resolve(
+                "solar_reader",
+                grid_meter::MeasuringSystem::Setup1P,
+                "BY24600320012",
+            )?,
+            log: file.log.resolve("solar_reader")?,
+            mqtt: file.mqtt.resolve("solar_reader", "solar-reader", "solar")?,
+            notifications: resolve_notifications(
+                file.notifications,
+                "solar_reader",
+                NOTIFICATION_EVENTS,
+            )?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{file, invalid_key, lock_env};
+
+    #[test]
+    fn registers_have_to_fit_in_one_read() {
+        let _env = lock_env();
+        let resolve = |registers| {
+            SolarReaderConfig::resolve(
+                file(&format!(
+                    "[solar_reader]
+                    database_url = 'postgres://localhost/solar'
+                    inverter_address = '192.168.1.50:502'
+                    grid_meter.address = '127.0.0.1:5021'
+
+                    [solar_reader.registers]
+                    {registers}"
+                ))
+                .solar_reader,
+            )
+        };
+
+        assert_eq!(resolve("").unwrap().registers.read_range(), (0x0109, 42));
+        assert_eq!(
+            invalid_key(resolve("total_energy = 0x0200")),
+            "solar_reader.registers"
+        );
+    }
+}
@@ -1,41 +1,59 @@
 use std::{
-    env,
-    net::SocketAddr,
     sync::{Arc, Mutex},
     time::Duration,
 };
 
 use backoff::backoff::Backoff;
 use grid_meter::InstantaneousData;
+use mqtt_publisher::{
+    MqttPublisher,
+    discovery::{Device, Quantity, SensorConfig},
+};
+use notifier::{Notification, Notifier, Priority};
+use serde::Serialize;
+use settings::SolarReaderConfig;
 use sqlx::{Pool, Postgres, postgres::PgPool};
 use tokio::time::timeout;
 use tokio_modbus::client::Reader;
+use tracing::Instrument;
+
+mod metrics;
+mod retention;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
+    let config = SolarReaderConfig::load()?;
+    init_logging(&config.log);
+    let notifier = Notifier::start(config.notifications.clone());
 
     let grid_meter_data = Arc::new(std::sync::Mutex::new(
         grid_meter::InstantaneousData::default(),
     ));
-    let grid_meter_address = env::var("GRID_METER_ADDRESS")?.parse().unwrap();
     tokio::spawn({
         let grid_meter_data = grid_meter_data.clone();
+        let grid_meter_config = config.grid_meter.clone();
         async move {
             grid_meter::run_grid_meter_server(
-                grid_meter_address,
+                grid_meter_config.address,
                 grid_meter_data,
-                grid_meter::MeasuringSystem::Setup1P,
-                b"BY24600320012\0",
+                grid_meter_config.measuring_system,
+                grid_meter_config.serial_number,
             )
             .await
             .unwrap();
         }
     });
 
-    println!("Connecting to database");
-    let pool = PgPool::connect(&env::var("DATABASE_URL")?).await?;
-    println!("Running database migrations");
+    if let Some(metrics_address) = config.metrics_address {
+        tokio::spawn(async move {
+            metrics::run_metrics_server(metrics_address).await.unwrap();
+        });
+    }
+
+    tracing::info!("Connecting to database");
+    let pool = PgPool::connect(&config.database_url).await?;
+    tracing::info!("Running database migrations");
     sqlx::migrate!().run(&pool).await?;
 
     let last_row = sqlx::query!("SELECT * FROM solar_data_points ORDER BY time DESC LIMIT 1")
@@ -43,7 +61,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
 
     if let Some(row) = last_row {
-        println!("Starting total energy: {}", row.total_energy);
+        tracing::info!("Starting total energy: {}", row.total_energy);
 
         {
             let mut grid_meter_data = grid_meter_data.lock().unwrap();
@@ -53,9 +71,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    println!("Getting inverter sock addr");
-    let addr = &env::var("INVERTER_SOCKADDR")?.parse()?;
-    println!("Ready");
+    if let Some(retention_days) = config.raw_retention_days {
+        let retention_config = retention::RetentionConfig {
+            max_age: chrono::TimeDelta::days(retention_days.into()),
+            dry_run: config.retention_dry_run,
+        };
+        tracing::info!("Thinning solar data older than {retention_days} days");
+        tokio::spawn(retention::run_retention(pool.clone(), retention_config));
+    }
+
+    let mqtt_publisher = config.mqtt.clone().map(MqttPublisher::start);
+    if let Some(mqtt_publisher) = &mqtt_publisher {
+        mqtt_publisher.set_discovery(&discovery_configs(
+            mqtt_publisher,
+            config.grid_meter.serial_number,
+        ));
+    }
+
+    tracing::info!("Ready");
 
     let mut backoff = backoff::ExponentialBackoffBuilder::new()
         .with_max_interval(Duration::from_secs(300))
@@ -64,11 +97,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_max_elapsed_time(None)
         .build();
 
+    // Set once a disconnect is notified, so failing reconnects aren't
+    let mut disconnect_notified = false;
+
     loop {
         let connect_time = tokio::time::Instant::now();
 
-        let result = connect_and_run(*addr, &pool, &grid_meter_data).await;
-        println!("Connection ended with: {}", result.as_ref().unwrap_err());
+        let result = connect_and_run(
+            &config,
+            &pool,
+            &grid_meter_data,
+            mqtt_publisher.as_ref(),
+            &notifier,
+            &mut disconnect_notified,
+        )
+        .await;
+        let error = result.as_ref().unwrap_err();
+        tracing::warn!("Connection ended with: {error}");
+        metrics::METRICS.connection_errors.inc();
+
+        if let Error::Sqlx(_) = error {
+            notifier.notify(
+                Notification::new(
+                    "database_failed",
+                    Priority::High,
+                    "Solar reader database failed",
+                    format!("The solar reader stops: {error}"),
+                )
+                .field("error", error),
+            );
+            notifier.flush(Duration::from_secs(30)).await;
+        } else if !disconnect_notified {
+            disconnect_notified = true;
+            notifier.notify(
+                Notification::new(
+                    "inverter_disconnected",
+                    Priority::Low,
+                    "Inverter disconnected",
+                    format!("Lost the connection to the inverter: {error}"),
+                )
+                .field("error", error),
+            );
+        }
 
         {
             let mut grid_meter_data = grid_meter_data.lock().unwrap();
@@ -91,48 +161,86 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 }
 
+#[tracing::instrument(skip_all, fields(inverter = %config.inverter_address))]
 async fn connect_and_run(
-    addr: SocketAddr,
+    config: &SolarReaderConfig,
     pool: &Pool<Postgres>,
     grid_meter_data: &Mutex<InstantaneousData>,
+    mqtt_publisher: Option<&MqttPublisher>,
+    notifier: &Notifier,
+    disconnect_notified: &mut bool,
 ) -> Result<(), Error> {
-    println!("Trying to connect to: {addr}");
+    let addr = config.inverter_address;
+    tracing::info!("Trying to connect to: {addr}");
     let mut ctx = timeout(
         Duration::from_secs(60),
-        tokio_modbus::client::tcp::connect_slave(addr, tokio_modbus::Slave(1)),
+        tokio_modbus::client::tcp::connect_slave(
+            addr,
+            tokio_modbus::Slave(config.inverter_slave_id),
+        ),
     )
     .await??;
 
-    println!("Connected!");
+    tracing::info!("Connected!");
 
     let mut interval = tokio::time::interval(Duration::from_secs(1));
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
+    let registers = config.registers;
+    let (first_register, register_count) = registers.read_range();
     let mut first_data = true;
 
-    #[allow(clippy::eq_op)]
     loop {
         let realtime_data = timeout(
             Duration::from_secs(60),
-            ctx.read_holding_registers(0x0109, 0x0133 - 0x0109),
+            ctx.read_holding_registers(first_register, register_count),
         )
+        .instrument(tracing::debug_span!(
+            "modbus_read",
+            first_register,
+            register_count
+        ))
         .await???;
+        let register = |address: u16| realtime_data[(address - first_register) as usize];
 
         if first_data {
             first_data = false;
-            println!("Received the first data: {realtime_data:X?}");
+            tracing::info!("Received the first data: {realtime_data:X?}");
+
+            if *disconnect_notified {
+                *disconnect_notified = false;
+                notifier.notify(Notification::new(
+                    "inverter_reconnected",
+                    Priority::Low,
+                    "Inverter reconnected",
+                    format!("Reading the inverter at {addr} again"),
+                ));
+            }
         }
 
-        let pv1_power = realtime_data[0x09 - 0x09];
-        let inv_temp = realtime_data[0x11 - 0x09] as i16 as f32 / 10.0;
-        let power = realtime_data[0x13 - 0x09];
-        let total_energy = (((realtime_data[0x31 - 0x09] as u32) << 16)
-            | realtime_data[0x32 - 0x09] as u32) as f32
+        let pv1_power = register(registers.pv_power);
+        let inv_temp = register(registers.inverter_temperature) as i16 as f32 / 10.0;
+        let power = register(registers.output_power);
+        let total_energy = (((register(registers.total_energy) as u32) << 16)
+            | register(registers.total_energy + 1) as u32) as f32
             / 100.0;
         let now = chrono::Utc::now();
+        tracing::debug!(
+            pv_power = pv1_power,
+            output_power = power,
+            total_energy,
+            "Read inverter"
+        );
+
+        metrics::METRICS.inverter_reads.inc();
+        metrics::METRICS.pv_power.set(pv1_power as f64);
+        metrics::METRICS.output_power.set(power as f64);
+        metrics::METRICS.total_energy.set(total_energy as f64);
+        metrics::METRICS.inverter_temperature.set(inv_temp as f64);
 
         interval.tick().await;
 
+        let timer = metrics::METRICS.database_insert_duration.start_timer();
         sqlx::query!(
             "insert into solar_data_points values($1, $2, $3, $4, $5)",
             now,
@@ -142,12 +250,27 @@ async fn connect_and_run(
             inv_temp,
         )
         .execute(pool)
+        .instrument(tracing::debug_span!("database_insert"))
         .await?;
+        timer.observe_duration();
+
+        if let Some(mqtt_publisher) = mqtt_publisher {
+            mqtt_publisher.publish_json(
+                "inverter",
+                &InverterState {
+                    time: now,
+                    active_power_output: power,
+                    active_power_input: pv1_power,
+                    total_energy,
+                    inverter_temperature: inv_temp,
+                },
+            );
+        }
 
         {
-            let l1_voltage = realtime_data[0x16 - 0x09] as f32 / 10.0;
-            let l1_current = realtime_data[0x17 - 0x09] as f32 / 100.0;
-            let l1_power = realtime_data[0x1A - 0x09] as f32;
+            let l1_voltage = register(registers.grid_voltage) as f32 / 10.0;
+            let l1_current = register(registers.grid_current) as f32 / 100.0;
+            let l1_power = register(registers.grid_power) as f32;
 
             let mut grid_meter_data = grid_meter_data.lock().unwrap();
             grid_meter_data.v_l1_n = (l1_voltage * 10.0).round() as i32;
@@ -164,6 +287,76 @@ async fn connect_and_run(
     }
 }
 
+fn init_logging(config: &settings::LogConfig) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::new(&config.level));
+    if config.json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
+
+#[derive(Serialize)]
+struct InverterState {
+    time: chrono::DateTime<chrono::Utc>,
+    /// W delivered to net
+    active_power_output: u16,
+    /// W delivered from panels
+    active_power_input: u16,
+    /// kWh
+    total_energy: f32,
+    /// C
+    inverter_temperature: f32,
+}
+
+/// The inverter is identified by the serial number of its grid meter, so every solar-reader has its own device
+fn discovery_configs(mqtt_publisher: &MqttPublisher, serial_number: &[u8]) -> Vec<SensorConfig> {
+    let serial_number = String::from_utf8_lossy(serial_number);
+    let device = Device {
+        identifiers: vec![serial_number.trim_end_matches('\0').into()],
+        name: "Solar inverter".into(),
+        manufacturer: None,
+        model: None,
+    };
+    let state_topic = mqtt_publisher.topic("inverter");
+
+    vec![
+        SensorConfig::new(
+            &device,
+            "pv_power",
+            "PV power",
+            Quantity::Power,
+            state_topic.clone(),
+            "{{ value_json.active_power_input }}",
+        ),
+        SensorConfig::new(
+            &device,
+            "output_power",
+            "Output power",
+            Quantity::Power,
+            state_topic.clone(),
+            "{{ value_json.active_power_output }}",
+        ),
+        SensorConfig::new(
+            &device,
+            "total_energy",
+            "Total energy",
+            Quantity::EnergyKilowattHour,
+            state_topic.clone(),
+            "{{ value_json.total_energy }}",
+        ),
+        SensorConfig::new(
+            &device,
+            "inverter_temperature",
+            "Inverter temperature",
+            Quantity::Temperature,
+            state_topic,
+            "{{ value_json.inverter_temperature }}",
+        ),
+    ]
+}
+
 #[derive(thiserror::Error, Debug)]
 enum Error {
     #[error("IO error: {0}")]
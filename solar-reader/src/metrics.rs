@@ -0,0 +1,82 @@
+use std::{net::SocketAddr, sync::LazyLock};
+
+use axum::{Router, routing::get};
+use metrics_registry::MetricsRegistry;
+use prometheus::{Gauge, Histogram, HistogramOpts, IntCounter};
+use tokio::net::TcpListener;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: MetricsRegistry,
+
+    pub pv_power: Gauge,
+    pub output_power: Gauge,
+    pub total_energy: Gauge,
+    pub inverter_temperature: Gauge,
+
+    pub inverter_reads: IntCounter,
+    pub connection_errors: IntCounter,
+    pub database_insert_duration: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = MetricsRegistry::new("solar");
+
+        let metrics = Self {
+            pv_power: Gauge::new("pv_power_watts", "Power delivered by the panels").unwrap(),
+            output_power: Gauge::new("output_power_watts", "Power delivered to the net").unwrap(),
+            total_energy: Gauge::new("total_energy_kwh", "Energy counter of the inverter").unwrap(),
+            inverter_temperature: Gauge::new(
+                "inverter_temperature_celsius",
+                "Temperature of the inverter",
+            )
+            .unwrap(),
+            inverter_reads: IntCounter::new(
+                "inverter_reads_total",
+                "Successful reads of the inverter registers",
+            )
+            .unwrap(),
+            connection_errors: IntCounter::new(
+                "connection_errors_total",
+                "Times the connection to the inverter ended with an error",
+            )
+            .unwrap(),
+            database_insert_duration: Histogram::with_opts(HistogramOpts::new(
+                "database_insert_duration_seconds",
+                "Time it takes to insert a reading into the database",
+            ))
+            .unwrap(),
+            registry,
+        };
+
+        for collector in [
+            Box::new(metrics.pv_power.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.output_power.clone()),
+            Box::new(metrics.total_energy.clone()),
+            Box::new(metrics.inverter_temperature.clone()),
+            Box::new(metrics.inverter_reads.clone()),
+            Box::new(metrics.connection_errors.clone()),
+            Box::new(metrics.database_insert_duration.clone()),
+        ] {
+            metrics.registry.register(collector);
+        }
+
+        metrics
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        self.registry.encode()
+    }
+}
+
+/// Serves the metrics on `/metrics`
+pub async fn run_metrics_server(socket_addr: SocketAddr) -> std::io::Result<()> {
+    let app = Router::new().route("/metrics", get(|| async { METRICS.encode() }));
+
+    tracing::info!("Starting up metrics server on {socket_addr}");
+    let listener = TcpListener::bind(socket_addr).await?;
+    axum::serve(listener, app).await
+}
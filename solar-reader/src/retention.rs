@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use chrono::{DurationRound, TimeDelta, Utc};
+use sqlx::postgres::PgPool;
+
+/// Rows are thinned in chunks of this much time, so a large backlog doesn't end up in one huge transaction
+const THIN_CHUNK: TimeDelta = TimeDelta::days(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionConfig {
+    /// Solar data older than this is thinned to one row per minute
+    pub max_age: TimeDelta,
+    /// Only log what would be deleted
+    pub dry_run: bool,
+}
+
+/// Thins old solar data once a day, keeping the first row of every minute.
+///
+/// There are no rollups of the solar data, so the thinned rows are what long range panels use.
+pub async fn run_retention(pool: PgPool, config: RetentionConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    // Everything before this has already been thinned
+    let mut thinned_until = None;
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = apply_retention(&pool, config, &mut thinned_until).await {
+            tracing::error!("Could not apply retention: {e}");
+        }
+    }
+}
+
+async fn apply_retention(
+    pool: &PgPool,
+    config: RetentionConfig,
+    thinned_until: &mut Option<chrono::DateTime<Utc>>,
+) -> Result<(), sqlx::Error> {
+    // Chunks start and end on whole minutes, so no minute is split between two of them
+    let cutoff = (Utc::now() - config.max_age)
+        .duration_trunc(TimeDelta::minutes(1))
+        .unwrap();
+
+    let mut start = match *thinned_until {
+        Some(thinned_until) => thinned_until,
+        None => {
+            let Some(start) = sqlx::query_scalar!("SELECT min(time) FROM solar_data_points")
+                .fetch_one(pool)
+                .await?
+            else {
+                return Ok(());
+            };
+            start.duration_trunc(TimeDelta::minutes(1)).unwrap()
+        }
+    };
+
+    let mut deleted = 0;
+    while start < cutoff {
+        let end = (start + THIN_CHUNK).min(cutoff);
+
+        if config.dry_run {
+            deleted += sqlx::query_scalar!(
+                r#"SELECT count(*) - count(DISTINCT date_trunc('minute', time)) AS "count!"
+                FROM solar_data_points
+                WHERE time >= $1 AND time < $2"#,
+                start,
+                end,
+            )
+            .fetch_one(pool)
+            .await? as u64;
+        } else {
+            deleted += sqlx::query!(
+                "DELETE FROM solar_data_points
+                WHERE time >= $1 AND time < $2 AND time NOT IN (
+                    SELECT min(time) FROM solar_data_points
+                    WHERE time >= $1 AND time < $2
+                    GROUP BY date_trunc('minute', time)
+                )",
+                start,
+                end,
+            )
+            .execute(pool)
+            .await?
+            .rows_affected();
+        }
+
+        start = end;
+    }
+
+    if config.dry_run {
+        tracing::info!(
+            "Retention dry run: would delete {deleted} solar data points before {cutoff}"
+        );
+    } else {
+        *thinned_until = Some(start);
+        if deleted > 0 {
+            tracing::info!("Retention: thinned {deleted} solar data points before {cutoff}");
+        }
+    }
+
+    Ok(())
+}